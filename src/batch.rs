@@ -0,0 +1,164 @@
+use std::ops::Index;
+use fixed::types::{I32F32, I64F64};
+use rayon::prelude::*;
+use crate::hierarchy::{query_aabb, Hierarchy};
+use crate::morton::to_hilbert;
+use crate::nearest::{nearest, within_radius};
+use crate::position::{EntityPos, EntityPosExt, AABB};
+use crate::ray::Ray;
+
+///one query of a batch, every kind answers with a list of entities, each with a distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Query {
+    ///every entity overlapping the box, in tree order, the distances are 0
+    Region(AABB),
+    ///every entity hit by the ray, sorted by the distance along the ray
+    Ray(Ray),
+    ///the closest entity hit by the ray, if any, with the distance along the ray
+    RayClosest(Ray),
+    ///an entity hit by the ray, if any, not necessarily the closest one, enough for line of sight checks, the distance is 0
+    RayAny(Ray),
+    ///the k closest entities, sorted by squared distance, see `nearest::nearest`
+    Nearest(EntityPos, usize),
+    ///every entity within the radius, sorted by squared distance
+    Radius(EntityPos, I32F32),
+}
+
+impl Query {
+    ///where the query happens, close queries are run one after the other so they walk the same nodes
+    fn position(&self) -> EntityPos {
+        match self {
+            Query::Region(aabb) => aabb.center(),
+            Query::Ray(ray) | Query::RayClosest(ray) | Query::RayAny(ray) => *ray.origin(),
+            Query::Nearest(point, _) | Query::Radius(point, _) => *point,
+        }
+    }
+
+    ///the entities answering the query, with the distance described on each kind
+    pub fn run(&self, tree: &(impl Hierarchy + ?Sized)) -> Vec<(usize, I64F64)> {
+        let along_ray = |(entity, distance): (usize, I32F32)| (entity, I64F64::from_num(distance));
+        match self {
+            Query::Region(aabb) => query_aabb(tree, aabb).into_iter().map(|entity| (entity, I64F64::ZERO)).collect(),
+            Query::Ray(ray) => ray.cast_all(tree).into_iter().map(along_ray).collect(),
+            Query::RayClosest(ray) => ray.cast_closest(tree).into_iter().map(along_ray).collect(),
+            Query::RayAny(ray) => ray.cast_any(tree).into_iter().map(|entity| (entity, I64F64::ZERO)).collect(),
+            Query::Nearest(point, k) => nearest(tree, point, *k),
+            Query::Radius(point, radius) => within_radius(tree, point, *radius),
+        }
+    }
+}
+
+///results of a batch, the entities of every query are stored back to back in a single vec
+pub struct BatchResults {
+    offsets: Vec<usize>, //the results of query i are entities[offsets[i]..offsets[i + 1]]
+    entities: Vec<usize>,
+    distances: Vec<I64F64>, //indexed like entities
+}
+
+impl BatchResults {
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[usize]> + '_ {
+        self.offsets.windows(2).map(|bounds| &self.entities[bounds[0]..bounds[1]])
+    }
+
+    ///distance of each entity of this query, in the same order as the entities, see `Query` for what it measures
+    pub fn distances(&self, query: usize) -> &[I64F64] {
+        &self.distances[self.offsets[query]..self.offsets[query + 1]]
+    }
+
+    ///entities of this query with their distance
+    pub fn hits(&self, query: usize) -> impl Iterator<Item = (usize, I64F64)> + '_ {
+        self[query].iter().copied().zip(self.distances(query).iter().copied())
+    }
+}
+
+impl Index<usize> for BatchResults {
+    type Output = [usize];
+
+    fn index(&self, query: usize) -> &[usize] {
+        &self.entities[self.offsets[query]..self.offsets[query + 1]]
+    }
+}
+
+///answer every query in parallel against the same tree, results are indexed like `queries`
+///the queries are run in the order of the hilbert code of their position, so each worker gets queries close to each other
+pub fn run_batch(tree: &(impl Hierarchy + Sync + ?Sized), queries: &[Query]) -> BatchResults {
+    let mut order: Vec<(u128, usize)> = queries.par_iter().enumerate().map(|(i, query)| (to_hilbert(query.position().block_pos()), i)).collect();
+    order.par_sort_unstable();
+
+    //every worker fills its own vecs, no lock is needed, they are only put back in query order once every query is done
+    let mut answers: Vec<(usize, Vec<(usize, I64F64)>)> = Vec::with_capacity(queries.len());
+    order.into_par_iter().map(|(_, i)| (i, queries[i].run(tree))).collect_into_vec(&mut answers);
+
+    let mut by_query = vec![Vec::new(); queries.len()];
+    for (i, answer) in answers {
+        by_query[i] = answer;
+    }
+    let mut offsets = Vec::with_capacity(queries.len() + 1);
+    offsets.push(0);
+    for answer in by_query.iter() {
+        offsets.push(offsets.last().unwrap() + answer.len());
+    }
+
+    let (entities, distances) = by_query.concat().into_iter().unzip();
+    BatchResults {
+        offsets,
+        entities,
+        distances,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::bvh5;
+    use crate::position::new_fixed_vec;
+    use crate::scene::{Scene, SceneConfig};
+
+    #[test]
+    fn batch_matches_single_queries() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let config = SceneConfig {
+            count: 2000,
+            range: -100..100,
+            half_size: 1..=5,
+        };
+        let mut tree = bvh5::BVH::new();
+        tree.build(Scene::Clusters.generate(&mut rng, &config));
+
+        let queries: Vec<Query> = (0..500).map(|i| {
+            let point: EntityPos = new_fixed_vec(rng.gen_range(-100..100), rng.gen_range(-100..100), rng.gen_range(-100..100));
+            let ray = Ray::new(point, new_fixed_vec(rng.gen_range(-5..5), 1, rng.gen_range(-5..5)), I32F32::from_num(100));
+            match i % 6 {
+                0 => Query::Region(AABB::from_center(point, new_fixed_vec(10, 10, 10))),
+                1 => Query::Ray(ray),
+                2 => Query::RayClosest(ray),
+                3 => Query::RayAny(ray),
+                4 => Query::Nearest(point, rng.gen_range(0..20)),
+                _ => Query::Radius(point, I32F32::from_num(rng.gen_range(0..20))),
+            }
+        }).collect();
+
+        let results = run_batch(&tree, &queries);
+        assert_eq!(results.len(), queries.len());
+        for (i, query) in queries.iter().enumerate() {
+            assert_eq!(results.hits(i).collect::<Vec<_>>(), query.run(&tree), "query {i}: {query:?}");
+            match query {
+                Query::RayClosest(ray) => assert_eq!(results.hits(i).next(), ray.cast_closest(&tree).map(|(entity, distance)| (entity, I64F64::from_num(distance))), "query {i}"),
+                Query::RayAny(ray) => assert_eq!(results[i].first().copied(), ray.cast_any(&tree), "query {i}"),
+                _ => {}
+            }
+        }
+        assert!(results.iter().any(|entities| !entities.is_empty()));
+        assert!(run_batch(&tree, &[]).is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use std::ops::ControlFlow;
+use fixed::types::I32F32;
+use rayon::prelude::*;
+use crate::broadphase::{collector, counter, TASKS_PER_THREAD};
+use crate::hierarchy::Hierarchy;
+use crate::position::AABB;
+
+///every pair of intersecting entities with the first one in `a` and the second one in `b`
+///pairs inside a single tree are never reported, so a static world can be built once and checked against each tick's entities
+pub fn get_cross_pairs(a: &(impl Hierarchy + ?Sized), b: &(impl Hierarchy + ?Sized)) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    let _ = visit_cross_collisions(a, b, collector(&mut pairs));
+    pairs
+}
+
+pub fn get_cross_pairs_par(a: &(impl Hierarchy + Sync + ?Sized), b: &(impl Hierarchy + Sync + ?Sized)) -> Vec<(usize, usize)> {
+    starting_pairs(a, b).into_par_iter().fold(Vec::new, |mut pairs, (node_a, node_b)| {
+        let _ = recursive_cross_collision(a, b, node_a, node_b, &mut collector(&mut pairs));
+        pairs
+    }).reduce(Vec::new, |mut a, mut b| {
+        a.append(&mut b);
+        a
+    })
+}
+
+pub fn count_cross_collisions_par(a: &(impl Hierarchy + Sync + ?Sized), b: &(impl Hierarchy + Sync + ?Sized)) -> usize {
+    starting_pairs(a, b).into_par_iter().map(|(node_a, node_b)| {
+        let mut output = 0;
+        let _ = recursive_cross_collision(a, b, node_a, node_b, &mut counter(&mut output));
+        output
+    }).sum()
+}
+
+///call `visitor` with an entity of `a` and an entity of `b` for every intersecting cross pair, until it breaks
+pub fn visit_cross_collisions<B>(a: &(impl Hierarchy + ?Sized), b: &(impl Hierarchy + ?Sized), mut visitor: impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+    match (a.root(), b.root()) {
+        (Some(root_a), Some(root_b)) => recursive_cross_collision(a, b, root_a, root_b, &mut visitor),
+        _ => ControlFlow::Continue(()),
+    }
+}
+
+///same as `visit_cross_collisions`, stopping like `Broadphase::visit_collisions_par`
+pub fn visit_cross_collisions_par<B: Send>(a: &(impl Hierarchy + Sync + ?Sized), b: &(impl Hierarchy + Sync + ?Sized), visitor: impl Fn(usize, usize) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+    starting_pairs(a, b).into_par_iter().try_for_each(|(node_a, node_b)| {
+        recursive_cross_collision(a, b, node_a, node_b, &mut |entity_a, entity_b| visitor(entity_a, entity_b))
+    })
+}
+
+///the bipartite version of `recursive_collision_between_nodes`, `node_a` is always in `a` and `node_b` in `b`
+///`a` and `b` can be the same tree, as long as neither node is an ancestor of the other
+pub(crate) fn recursive_cross_collision<B>(a: &(impl Hierarchy + ?Sized), b: &(impl Hierarchy + ?Sized), node_a: usize, node_b: usize, output: &mut impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+    if !a.node_aabb(node_a).intersects(b.node_aabb(node_b)) { return ControlFlow::Continue(()); }
+    match split(a, b, node_a, node_b) {
+        Split::Leaves => output(a.entity(node_a), b.entity(node_b)),
+        Split::A(left, right) => {
+            recursive_cross_collision(a, b, left, node_b, output)?;
+            recursive_cross_collision(a, b, right, node_b, output)
+        }
+        Split::B(left, right) => {
+            recursive_cross_collision(a, b, node_a, left, output)?;
+            recursive_cross_collision(a, b, node_a, right, output)
+        }
+    }
+}
+
+enum Split {
+    Leaves,
+    A(usize, usize),
+    B(usize, usize),
+}
+
+///when both nodes are branches, the biggest one is opened, so the two sides shrink together
+fn split(a: &(impl Hierarchy + ?Sized), b: &(impl Hierarchy + ?Sized), node_a: usize, node_b: usize) -> Split {
+    match (a.children(node_a), b.children(node_b)) {
+        (None, None) => Split::Leaves,
+        (Some((left, right)), None) => Split::A(left, right),
+        (None, Some((left, right))) => Split::B(left, right),
+        (Some((left, right)), Some(children_b)) => {
+            if size(a.node_aabb(node_a)) >= size(b.node_aabb(node_b)) {
+                Split::A(left, right)
+            } else {
+                Split::B(children_b.0, children_b.1)
+            }
+        }
+    }
+}
+
+fn size(aabb: &AABB) -> I32F32 {
+    (0..3).fold(I32F32::ZERO, |total, axis| total.saturating_add(aabb.max()[axis].saturating_sub(aabb.min()[axis])))
+}
+
+///intersecting node pairs a few levels below the roots, enough of them to keep every rayon worker busy
+fn starting_pairs(a: &(impl Hierarchy + ?Sized), b: &(impl Hierarchy + ?Sized)) -> Vec<(usize, usize)> {
+    let (Some(root_a), Some(root_b)) = (a.root(), b.root()) else { return Vec::new(); };
+    let target = rayon::current_num_threads() * TASKS_PER_THREAD;
+    let mut pairs = vec![(root_a, root_b)];
+    while pairs.len() < target {
+        let mut split_any = false;
+        let mut next = Vec::with_capacity(pairs.len() * 2);
+        for (node_a, node_b) in pairs {
+            if !a.node_aabb(node_a).intersects(b.node_aabb(node_b)) { continue; }
+            match split(a, b, node_a, node_b) {
+                Split::Leaves => next.push((node_a, node_b)),
+                Split::A(left, right) => {
+                    split_any = true;
+                    next.extend([(left, node_b), (right, node_b)]);
+                }
+                Split::B(left, right) => {
+                    split_any = true;
+                    next.extend([(node_a, left), (node_a, right)]);
+                }
+            }
+        }
+        pairs = next;
+        if !split_any {
+            break;
+        }
+    }
+    pairs
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::brute_force::testing::{random_scene, LEAF_COUNTS, SEEDS};
+    use crate::{bvh4, bvh5};
+
+    fn check_cross_pairs(a: &(impl Hierarchy + Sync), b: &(impl Hierarchy + Sync), expected: &[(usize, usize)], context: &str) {
+        assert_eq!(sorted(get_cross_pairs(a, b)), expected, "{context}: wrong pairs");
+        assert_eq!(sorted(get_cross_pairs_par(a, b)), expected, "{context}: wrong par pairs");
+        if expected.len() > 3 {
+            let mut seen = 0;
+            let flow = visit_cross_collisions(a, b, |_, _| {
+                seen += 1;
+                if seen == 3 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+            });
+            assert_eq!((flow, seen), (ControlFlow::Break(()), 3), "{context}");
+            let seen = AtomicUsize::new(0);
+            let flow = visit_cross_collisions_par(a, b, |_, _| {
+                if seen.fetch_add(1, Ordering::Relaxed) >= 2 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+            });
+            assert_eq!(flow, ControlFlow::Break(()), "{context}");
+        }
+    }
+
+    ///unlike `normalize_pairs`, the order inside a pair matters here, the first entity comes from the first tree
+    fn sorted(mut pairs: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+        pairs.sort_unstable();
+        pairs
+    }
+
+    #[test]
+    fn matches_oracle() {
+        let (mut static4, mut moving4) = (bvh4::BVH::new(), bvh4::BVH::new());
+        let (mut static5, mut moving5) = (bvh5::BVH::new(), bvh5::BVH::new());
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for count in LEAF_COUNTS {
+                let static_leaves = random_scene(&mut rng, count);
+                let moving_count = rng.gen_range(0..=count);
+                let moving_leaves = random_scene(&mut rng, moving_count);
+                let mut expected = Vec::new();
+                for (a, aabb_a) in static_leaves.iter().enumerate() {
+                    for (b, aabb_b) in moving_leaves.iter().enumerate() {
+                        if aabb_a.intersects(aabb_b) {
+                            expected.push((a, b));
+                        }
+                    }
+                }
+                static4.build(static_leaves.clone());
+                static5.build(static_leaves);
+                moving4.build(moving_leaves.clone());
+                moving5.build(moving_leaves);
+                let context = format!("seed {seed} and {count} leaves");
+                check_cross_pairs(&static4, &moving4, &expected, &format!("bvh4 against bvh4 with {context}"));
+                check_cross_pairs(&static4, &moving5, &expected, &format!("bvh4 against bvh5 with {context}"));
+                check_cross_pairs(&static5, &moving4, &expected, &format!("bvh5 against bvh4 with {context}"));
+                check_cross_pairs(&static5, &moving5, &expected, &format!("bvh5 against bvh5 with {context}"));
+            }
+        }
+    }
+}
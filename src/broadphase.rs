@@ -0,0 +1,81 @@
+use std::convert::Infallible;
+use std::ops::ControlFlow;
+use crate::position::AABB;
+
+///common interface of every broadphase structure, so they can be swapped behind a generic parameter or a `Box<dyn Broadphase>`
+pub trait Broadphase {
+    ///short name used to print the results of the benchmarks
+    fn name(&self) -> &'static str;
+
+    ///clear the current state and rebuild the structure from scratch
+    fn build(&mut self, leaves: Vec<AABB>);
+
+    ///same as `build`, but using rayon, structures without a parallel builder simply use the sequential one
+    fn build_par(&mut self, leaves: Vec<AABB>) {
+        self.build(leaves);
+    }
+
+    ///build again from the boxes of the next tick, structures able to reuse the work of the previous build do it
+    ///the others, and the first call, do a `build_par`
+    fn rebuild(&mut self, leaves: Vec<AABB>) {
+        self.build_par(leaves);
+    }
+
+    ///count every pair of intersecting leaves, each pair is counted once
+    fn get_collision(&self) -> usize;
+
+    ///same as `get_collision`, but using rayon, structures without a parallel traversal simply use the sequential one
+    fn get_collision_par(&self) -> usize {
+        self.get_collision()
+    }
+
+    ///every pair of intersecting leaves, as indices in the vec given to `build`
+    ///each pair is reported once, in no particular order
+    fn get_collision_pairs(&self) -> Vec<(usize, usize)>;
+
+    ///same as `get_collision_pairs`, but using rayon, the order of the pairs may change between runs
+    fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        self.get_collision_pairs()
+    }
+
+    ///call `visitor` with the indices of every intersecting pair, until it returns `ControlFlow::Break`
+    ///the returned value tells if the traversal was stopped early
+    fn visit_collisions(&self, visitor: &mut dyn FnMut(usize, usize) -> ControlFlow<()>) -> ControlFlow<()>;
+
+    ///same as `visit_collisions`, but `visitor` is shared between the rayon workers
+    ///each worker stops as soon as `visitor` breaks, but the others only stop when it breaks for them too, so once it breaks it should keep breaking
+    fn visit_collisions_par(&self, visitor: &(dyn Fn(usize, usize) -> ControlFlow<()> + Sync)) -> ControlFlow<()> {
+        self.visit_collisions(&mut |a, b| visitor(a, b))
+    }
+
+    ///every entity whose box overlaps `region`, as indices in the vec given to `build`, in no particular order
+    ///the trees do it through `hierarchy::query_aabb`, which is also where the other queries are
+    fn query_aabb(&self, region: &AABB) -> Vec<usize>;
+
+    ///indices of the nodes from the root down to the leaf of this entity, in the structure's own numbering
+    ///only meant for debugging, it can be O(n), structures that aren't trees return None
+    fn leaf_path(&self, _entity: usize) -> Option<Vec<usize>> {
+        None
+    }
+}
+
+///how many tasks per rayon worker the parallel traversals split their work into, more tasks balance the load better
+pub(crate) const TASKS_PER_THREAD: usize = 16;
+
+///visitor counting every pair it's called with, it never stops the traversal
+#[inline]
+pub fn counter(count: &mut usize) -> impl FnMut(usize, usize) -> ControlFlow<Infallible> + '_ {
+    move |_, _| {
+        *count += 1;
+        ControlFlow::Continue(())
+    }
+}
+
+///visitor pushing every pair it's called with, it never stops the traversal
+#[inline]
+pub fn collector(pairs: &mut Vec<(usize, usize)>) -> impl FnMut(usize, usize) -> ControlFlow<Infallible> + '_ {
+    move |a, b| {
+        pairs.push((a, b));
+        ControlFlow::Continue(())
+    }
+}
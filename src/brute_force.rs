@@ -0,0 +1,293 @@
+use std::ops::ControlFlow;
+use rayon::prelude::*;
+use crate::broadphase::Broadphase;
+use crate::position::AABB;
+
+///reference implementation, test every pair with `AABB::intersects`
+///it's O(n²), only here to check the results of the other structures
+#[derive(Default)]
+pub struct BruteForce {
+    leaves: Vec<AABB>,
+}
+
+impl BruteForce {
+    pub fn new() -> Self {
+        Self {
+            leaves: Vec::new(),
+        }
+    }
+
+    pub fn build(&mut self, leaves: Vec<AABB>) {
+        self.leaves = leaves;
+    }
+
+    pub fn get_collision(&self) -> usize {
+        let mut collisions = 0;
+        let _ = self.visit_collisions(|_, _| {
+            collisions += 1;
+            ControlFlow::<()>::Continue(())
+        });
+        collisions
+    }
+
+    pub fn get_collision_par(&self) -> usize {
+        (0..self.leaves.len()).into_par_iter().map(|i| {
+            let aabb = &self.leaves[i];
+            self.leaves[i + 1..].iter().filter(|other| aabb.intersects(other)).count()
+        }).sum()
+    }
+
+    ///every intersecting pair, the lowest index comes first and pairs are sorted
+    pub fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(|a, b| {
+            pairs.push((a, b));
+            ControlFlow::<()>::Continue(())
+        });
+        pairs
+    }
+
+    ///every leaf overlapping `region`, in increasing order
+    pub fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        (0..self.leaves.len()).filter(|&i| self.leaves[i].intersects(region)).collect()
+    }
+
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        for (i, aabb1) in self.leaves.iter().enumerate() {
+            for (j, aabb2) in self.leaves.iter().enumerate().skip(i + 1) {
+                if aabb1.intersects(aabb2) {
+                    visitor(i, j)?;
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+impl Broadphase for BruteForce {
+    fn name(&self) -> &'static str {
+        "brute force"
+    }
+
+    fn build(&mut self, leaves: Vec<AABB>) {
+        BruteForce::build(self, leaves);
+    }
+
+    fn get_collision(&self) -> usize {
+        BruteForce::get_collision(self)
+    }
+
+    fn get_collision_par(&self) -> usize {
+        BruteForce::get_collision_par(self)
+    }
+
+    fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        BruteForce::get_collision_pairs(self)
+    }
+
+    fn visit_collisions(&self, visitor: &mut dyn FnMut(usize, usize) -> ControlFlow<()>) -> ControlFlow<()> {
+        BruteForce::visit_collisions(self, visitor)
+    }
+
+    fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        BruteForce::query_aabb(self, region)
+    }
+}
+
+///sort the pairs and put the lowest index first, so the output of two structures can be compared
+pub fn normalize_pairs(mut pairs: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    for pair in pairs.iter_mut() {
+        if pair.0 > pair.1 {
+            *pair = (pair.1, pair.0);
+        }
+    }
+    pairs.sort_unstable();
+    pairs
+}
+
+///random scenes shared by the differential tests of every module
+#[cfg(test)]
+pub(crate) mod testing {
+    use fixed::types::I32F32;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use crate::position::{new_fixed_vec, EntityPos, AABB};
+
+    pub const SEEDS: u64 = 32;
+    pub const LEAF_COUNTS: [usize; 10] = [0, 1, 2, 3, 4, 5, 7, 16, 33, 257];
+
+    pub fn random_coord(rng: &mut StdRng, range: i32, integer: bool) -> I32F32 {
+        if integer {
+            I32F32::from_num(rng.gen_range(-range..range))
+        } else {
+            I32F32::from_num(rng.gen_range(-range as f64..range as f64))
+        }
+    }
+
+    ///random boxes packed in a small world, coordinates are either integers, so a lot of boxes touch exactly, or fractional
+    ///the scale makes some scenes span many grid cells on both sides of zero
+    pub fn random_scene(rng: &mut StdRng, count: usize) -> Vec<AABB> {
+        let scale = [1, 16, 64][rng.gen_range(0..3)];
+        let range = (2 + (count as f64).cbrt() as i32 * 3) * scale; //roughly a few collisions per box, whatever the count
+        let integer = rng.gen_bool(0.5);
+        (0..count).map(|_| {
+            let center: EntityPos = new_fixed_vec(
+                random_coord(rng, range, integer),
+                random_coord(rng, range, integer),
+                random_coord(rng, range, integer),
+            );
+            let half_size = new_fixed_vec(rng.gen_range(0..4) * scale, rng.gen_range(0..4) * scale, rng.gen_range(0..4) * scale);
+            AABB::from_center(center, half_size)
+        }).collect()
+    }
+
+    pub fn random_displacement(rng: &mut StdRng) -> EntityPos {
+        let scale = [0, 1, 40][rng.gen_range(0..3)]; //still, slow or fast enough to jump over most boxes
+        if scale == 0 {
+            return EntityPos::zeros();
+        }
+        new_fixed_vec(random_coord(rng, scale, false), random_coord(rng, scale, false), random_coord(rng, scale, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::scene::{Scene, SceneConfig};
+    use crate::{bvh2, bvh3, bvh4, bvh5, dynamic, homemade, static_grid};
+    use super::testing::{random_scene, LEAF_COUNTS, SEEDS};
+
+    ///every scene of the benchmark, shrunk so the oracle stays fast
+    fn benchmark_scenes(rng: &mut StdRng) -> Vec<Vec<AABB>> {
+        let config = SceneConfig {
+            count: 300,
+            range: -40..40,
+            half_size: 0..=3,
+        };
+        Scene::ALL.iter().map(|scene| scene.generate(rng, &config)).collect()
+    }
+
+    fn check(mut structure: impl Broadphase) {
+        let name = structure.name();
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut scenes: Vec<Vec<AABB>> = LEAF_COUNTS.iter().map(|&count| random_scene(&mut rng, count)).collect();
+            if seed < 4 {
+                scenes.extend(benchmark_scenes(&mut rng));
+            }
+            for leaves in scenes {
+                let count = leaves.len();
+                let mut oracle = BruteForce::new();
+                oracle.build(leaves.clone());
+                let expected = oracle.get_collision_pairs();
+
+                structure.build(leaves.clone());
+                let pairs = normalize_pairs(structure.get_collision_pairs());
+                assert_eq!(pairs, expected, "{name}: wrong pairs with seed {seed} and {count} leaves");
+                assert_eq!(structure.get_collision(), expected.len(), "{name}: wrong count with seed {seed} and {count} leaves");
+                assert_eq!(structure.get_collision_par(), expected.len(), "{name}: wrong par count with seed {seed} and {count} leaves");
+                let pairs = normalize_pairs(structure.get_collision_pairs_par());
+                assert_eq!(pairs, expected, "{name}: wrong par pairs with seed {seed} and {count} leaves");
+                for region in random_scene(&mut rng, 4) {
+                    let mut found = structure.query_aabb(&region);
+                    found.sort_unstable();
+                    assert_eq!(found, oracle.query_aabb(&region), "{name}: wrong region query with seed {seed} and {count} leaves");
+                }
+
+                structure.build_par(leaves);
+                let pairs = normalize_pairs(structure.get_collision_pairs_par());
+                assert_eq!(pairs, expected, "{name}: wrong pairs after build_par with seed {seed} and {count} leaves");
+            }
+        }
+    }
+
+    ///stop after the third pair, both sequentially and in parallel
+    fn check_early_exit(mut structure: impl Broadphase) {
+        let name = structure.name();
+        let mut rng = StdRng::seed_from_u64(0);
+        let leaves = random_scene(&mut rng, 257);
+        let mut oracle = BruteForce::new();
+        oracle.build(leaves.clone());
+        let expected = oracle.get_collision();
+        assert!(expected > 3);
+        structure.build(leaves);
+
+        let mut seen = 0;
+        let flow = structure.visit_collisions(&mut |_, _| {
+            seen += 1;
+            if seen == 3 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        assert_eq!(flow, ControlFlow::Break(()), "{name}");
+        assert_eq!(seen, 3, "{name}");
+
+        let seen = AtomicUsize::new(0);
+        let flow = structure.visit_collisions_par(&|_, _| {
+            if seen.fetch_add(1, Ordering::Relaxed) >= 2 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        assert_eq!(flow, ControlFlow::Break(()), "{name}");
+        assert!(seen.load(Ordering::Relaxed) < expected, "{name}");
+
+        let flow = structure.visit_collisions(&mut |_, _| ControlFlow::Continue(()));
+        assert_eq!(flow, ControlFlow::Continue(()), "{name}");
+    }
+
+    #[test]
+    fn brute_force_par_matches() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut oracle = BruteForce::new();
+        oracle.build(random_scene(&mut rng, 500));
+        assert_eq!(oracle.get_collision(), oracle.get_collision_par());
+        assert_eq!(oracle.get_collision(), oracle.get_collision_pairs().len());
+    }
+
+    #[test]
+    fn bvh2_matches_oracle() {
+        check(bvh2::BVH::new());
+        check_early_exit(bvh2::BVH::new());
+    }
+
+    #[test]
+    fn bvh3_matches_oracle() {
+        check(bvh3::BVH::new());
+        check_early_exit(bvh3::BVH::new());
+    }
+
+    #[test]
+    fn bvh4_matches_oracle() {
+        check(bvh4::BVH::new());
+        check_early_exit(bvh4::BVH::new());
+    }
+
+    #[test]
+    fn bvh4_sah_matches_oracle() {
+        check(bvh4::BVH::with_builder(bvh4::Builder::BinnedSah));
+        check_early_exit(bvh4::BVH::with_builder(bvh4::Builder::BinnedSah));
+    }
+
+    #[test]
+    fn bvh5_matches_oracle() {
+        check(bvh5::BVH::new());
+        check_early_exit(bvh5::BVH::new());
+    }
+
+    #[test]
+    fn dynamic_tree_matches_oracle() {
+        check(dynamic::DynamicTree::new());
+        check_early_exit(dynamic::DynamicTree::new());
+    }
+
+    #[test]
+    fn morton_list_matches_oracle() {
+        check(homemade::MortonList::new());
+        check_early_exit(homemade::MortonList::new());
+    }
+
+    #[test]
+    fn grid_matches_oracle() {
+        check(static_grid::GridTracker::new());
+        check_early_exit(static_grid::GridTracker::new());
+    }
+}
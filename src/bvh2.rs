@@ -1,5 +1,8 @@
 use std::cmp::PartialEq;
 use std::iter::repeat_with;
+use std::ops::ControlFlow;
+use crate::broadphase::{collector, counter, Broadphase};
+use crate::hierarchy::{query_aabb, Hierarchy};
 use crate::morton::to_hilbert;
 use crate::position::{AABB, EntityPosExt};
 
@@ -9,7 +12,7 @@ enum Node {
     Leaf{
         morton: u128,
         aabb: AABB,
-        // ... entity ID stuff here
+        index: usize, //index of the entity in the vec given to build, leaves are moved around by the sorting
     },
     Node {
         morton: u128,
@@ -37,25 +40,43 @@ impl Node {
     }
 }
 
-pub struct BVH {
+pub struct BVH<T = ()> {
     nodes: Vec<Node>,
+    payloads: Vec<T>, //user data of every entity, indexed by the `index` stored in the leaves
 }
 
 impl BVH {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn build(&mut self, leaves: Vec<AABB>) {
+        let len = leaves.len();
+        self.build_with_payload(leaves, vec![(); len]);
+    }
+}
+
+impl<T> Default for BVH<T> {
+    fn default() -> Self {
         Self {
             nodes: Vec::new(),
+            payloads: Vec::new(),
         }
     }
+}
+
+impl<T: Send + Sync> BVH<T> {
+    pub fn build_with_payload(&mut self, leaves: Vec<AABB>, payloads: Vec<T>) {
+        assert_eq!(leaves.len(), payloads.len());
+        self.payloads = payloads;
 
-    pub fn build(&mut self, leaves: Vec<AABB>) {
         let len = leaves.len();
 
         //setup the nodes vec
         self.nodes.clear();
-        self.nodes.reserve(2 * len - 1); //this formula doesn't come from out of nowhere, if you want to store n leaves, you need n-1 branches, so 2n-1 nodes in total
-        let iter = leaves.into_iter().map(|aabb| Node::Leaf { morton: to_hilbert(aabb.center().block_pos()) , aabb });
-        let fill_with_uninit = repeat_with(|| Node::Uninit).take(len -1 ); //n-1 branches...
+        self.nodes.reserve((2 * len).saturating_sub(1)); //this formula doesn't come from out of nowhere, if you want to store n leaves, you need n-1 branches, so 2n-1 nodes in total
+        let iter = leaves.into_iter().enumerate().map(|(index, aabb)| Node::Leaf { morton: to_hilbert(aabb.center().block_pos()) , aabb, index });
+        let fill_with_uninit = repeat_with(|| Node::Uninit).take(len.saturating_sub(1)); //n-1 branches...
         self.nodes.extend(iter);
         self.nodes.extend(fill_with_uninit);
 
@@ -108,51 +129,142 @@ impl BVH {
 
     pub fn get_collision(&self) -> usize {
         let mut collisions = 0;
+        let _ = self.visit_collisions(counter(&mut collisions));
+        collisions
+    }
+
+    ///every intersecting pair, as indices in the vec given to `build`
+    pub fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(collector(&mut pairs));
+        pairs
+    }
+
+    ///call `visitor` with the indices of every intersecting pair, until it breaks
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
         if let Some(Node::Node { left, right, .. }) = self.nodes.last() {
-            self.recursive_visit(*left, *right, &mut collisions);
+            self.recursive_visit(*left, *right, &mut visitor)?;
         }
-        collisions
+        ControlFlow::Continue(())
     }
 
     //this function doesn't check for collisions directly, it simply visits the tree (so maybe a linear iterator would be faster)
-    fn recursive_visit(&self, left: usize, right: usize, output: &mut usize) {
+    fn recursive_visit<B>(&self, left: usize, right: usize, output: &mut impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
         //this iterates over the tree but doesn't check for collisions
         if let Node::Node { left, right, .. } = &self.nodes[left] {
-            self.recursive_visit(*left, *right, output);
+            self.recursive_visit(*left, *right, output)?;
         }
         if let Node::Node { left, right, .. } = &self.nodes[right] {
-            self.recursive_visit(*left, *right, output);
+            self.recursive_visit(*left, *right, output)?;
         }
-        self.recursive_collision_between_nodes(left, right, output);
+        self.recursive_collision_between_nodes(left, right, output)
     }
 
     //this function check for intersections between nodes, this is the most expensive part of the algorithm, it's step node by node checking for intersections
-    fn recursive_collision_between_nodes(&self, left: usize, right: usize, output: &mut usize) {
+    fn recursive_collision_between_nodes<B>(&self, left: usize, right: usize, output: &mut impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
         let left_node = &self.nodes[left];
         let right_node = &self.nodes[right];
         let left_aabb = left_node.get_aabb();
         let right_aabb = right_node.get_aabb();
-        if !AABB::intersects(left_aabb, right_aabb) { return; } //no collision, nothing to do
+        if !AABB::intersects(left_aabb, right_aabb) { return ControlFlow::Continue(()); } //no collision, nothing to do
 
         match (left_node, right_node) {
-            (Node::Leaf { .. }, Node::Leaf { .. }) => { //case 1: both are leaves
-                *output += 1;
+            (Node::Leaf { index: left_index, .. }, Node::Leaf { index: right_index, .. }) => { //case 1: both are leaves
+                output(*left_index, *right_index)?;
             }
             (Node::Node { left: left_left, right: left_right, .. }, Node::Leaf { .. }) => { //case 2: left is a node, right is a leaf
-                self.recursive_collision_between_nodes(*left_left, right, output);
-                self.recursive_collision_between_nodes(*left_right, right, output);
+                self.recursive_collision_between_nodes(*left_left, right, output)?;
+                self.recursive_collision_between_nodes(*left_right, right, output)?;
             }
             (Node::Leaf { .. }, Node::Node { left: right_left, right: right_right, .. }) => { //case 3: left is a leaf, right is a node
-                self.recursive_collision_between_nodes(left, *right_left, output);
-                self.recursive_collision_between_nodes(left, *right_right, output);
+                self.recursive_collision_between_nodes(left, *right_left, output)?;
+                self.recursive_collision_between_nodes(left, *right_right, output)?;
             }
             (Node::Node { left: left_left, right: left_right, .. }, Node::Node { left: right_left, right: right_right, .. }) => { //case 4: both are nodes
-                self.recursive_collision_between_nodes(*left_left, *right_left, output);
-                self.recursive_collision_between_nodes(*left_left, *right_right, output);
-                self.recursive_collision_between_nodes(*left_right, *right_left, output);
-                self.recursive_collision_between_nodes(*left_right, *right_right, output);
+                self.recursive_collision_between_nodes(*left_left, *right_left, output)?;
+                self.recursive_collision_between_nodes(*left_left, *right_right, output)?;
+                self.recursive_collision_between_nodes(*left_right, *right_left, output)?;
+                self.recursive_collision_between_nodes(*left_right, *right_right, output)?;
             }
             _ => unreachable!(),
         }
+        ControlFlow::Continue(())
+    }
+
+    ///user data given to `build_with_payload` for the entity at this index
+    pub fn payload(&self, index: usize) -> &T {
+        &self.payloads[index]
+    }
+
+    ///nodes from the root down to the leaf of this entity, slow since we have no link to the parents
+    pub fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        let mut current = self.nodes.iter().position(|node| matches!(node, Node::Leaf { index, .. } if *index == entity))?;
+        let mut path = vec![current];
+        while let Some(parent) = self.nodes.iter().position(|node| matches!(node, Node::Node { left, right, .. } if *left == current || *right == current)) {
+            path.push(parent);
+            current = parent;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    ///same as `visit_collisions`, but hands back the payloads of the two entities
+    pub fn visit_collision_payloads<B>(&self, mut visitor: impl FnMut(&T, &T) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.visit_collisions(|a, b| visitor(&self.payloads[a], &self.payloads[b]))
+    }
+}
+
+///the leaves are first and the root is the last node
+impl<T: Send + Sync> Hierarchy for BVH<T> {
+    fn root(&self) -> Option<usize> {
+        self.nodes.len().checked_sub(1)
+    }
+
+    fn node_aabb(&self, node: usize) -> &AABB {
+        self.nodes[node].get_aabb()
+    }
+
+    fn children(&self, node: usize) -> Option<(usize, usize)> {
+        match self.nodes[node] {
+            Node::Node { left, right, .. } => Some((left, right)),
+            _ => None,
+        }
     }
-}
\ No newline at end of file
+
+    fn entity(&self, leaf: usize) -> usize {
+        match self.nodes[leaf] {
+            Node::Leaf { index, .. } => index,
+            _ => panic!("{leaf} isn't a leaf"),
+        }
+    }
+}
+
+impl Broadphase for BVH {
+    fn name(&self) -> &'static str {
+        "bvh2"
+    }
+
+    fn build(&mut self, leaves: Vec<AABB>) {
+        BVH::build(self, leaves);
+    }
+
+    fn get_collision(&self) -> usize {
+        BVH::get_collision(self)
+    }
+
+    fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        BVH::get_collision_pairs(self)
+    }
+
+    fn visit_collisions(&self, visitor: &mut dyn FnMut(usize, usize) -> ControlFlow<()>) -> ControlFlow<()> {
+        BVH::visit_collisions(self, visitor)
+    }
+
+    fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        query_aabb(self, region)
+    }
+
+    fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        BVH::leaf_path(self, entity)
+    }
+}
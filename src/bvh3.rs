@@ -1,5 +1,8 @@
 use std::cmp::Ordering;
+use std::ops::ControlFlow;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use crate::broadphase::{collector, counter, Broadphase};
+use crate::hierarchy::{query_aabb, Hierarchy};
 use crate::morton::to_hilbert;
 use crate::position::{AABB, EntityPosExt};
 
@@ -12,6 +15,11 @@ impl NodeIndex {
         self.0 & (1 << 31) == 0
     }
 
+    #[cfg(test)]
+    fn is_node(&self) -> bool {
+        !self.is_leaf()
+    }
+
     fn new_leaf(index: usize) -> Self {
         assert!(index < (1 << 31));
         Self(index as u32)
@@ -63,20 +71,35 @@ impl Ord for SortableNodeData {
     }
 }
 
-pub struct BVH {
+pub struct BVH<T = ()> {
     leaves: Vec<Leaf>,
     nodes: Vec<Node>,
+    payloads: Vec<T>, //parallel to leaves, they are never reordered
 }
 
 
 impl BVH {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn build(&mut self, leaves: Vec<AABB>) {
+        let len = leaves.len();
+        self.build_with_payload(leaves, vec![(); len]);
+    }
+}
+
+impl<T> Default for BVH<T> {
+    fn default() -> Self {
         Self {
             leaves: Vec::new(),
             nodes: Vec::new(),
+            payloads: Vec::new(),
         }
     }
+}
 
+impl<T: Send + Sync> BVH<T> {
     fn get_aabb(&self, node: NodeIndex) -> &AABB {
         if node.is_leaf() {
             &self.leaves[node.index()].aabb
@@ -85,13 +108,16 @@ impl BVH {
         }
     }
 
-    pub fn build(&mut self, leaves: Vec<AABB>) {
+    pub fn build_with_payload(&mut self, leaves: Vec<AABB>, payloads: Vec<T>) {
+        assert_eq!(leaves.len(), payloads.len());
+        self.payloads = payloads;
+
         //clear the current state, and reserve the necessary space
         self.leaves.clear();
         self.leaves.reserve(leaves.len());
         self.leaves.extend(leaves.into_iter().map(|aabb| Leaf { aabb }));
         self.nodes.clear();
-        self.nodes.reserve(self.leaves.len() - 1);
+        self.nodes.reserve(self.leaves.len().saturating_sub(1));
 
         //for this implementation, we also need additional space for building the tree
         let mut node_to_process: Vec<SortableNodeData> = self.leaves.iter().enumerate().map(|(i, leaf)| {
@@ -102,7 +128,7 @@ impl BVH {
         }).collect();
 
         loop {
-            if node_to_process.len() == 1 {
+            if node_to_process.len() <= 1 { //0 when there is no leaf at all
                 break;
             }
 
@@ -142,38 +168,69 @@ impl BVH {
     pub fn get_collision_par(&self) -> usize {
         self.nodes.par_iter().map(|node| { //this could be batched more efficiently, but I'm too lazy to do it
             let mut output = 0;
-            self.recursive_collision_between_nodes(node.left, node.right, &mut output);
+            let _ = self.recursive_collision_between_nodes(node.left, node.right, &mut counter(&mut output));
             output
         }).sum()
     }
 
     pub fn get_collision(&self) -> usize {
         let mut output = 0;
+        let _ = self.visit_collisions(counter(&mut output));
+        output
+    }
+
+    ///every intersecting pair, as indices in the vec given to `build`, leaves are never reordered so it's simply their index
+    pub fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(collector(&mut pairs));
+        pairs
+    }
+
+    pub fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        self.nodes.par_iter().fold(Vec::new, |mut pairs, node| {
+            let _ = self.recursive_collision_between_nodes(node.left, node.right, &mut collector(&mut pairs));
+            pairs
+        }).reduce(Vec::new, |mut a, mut b| {
+            a.append(&mut b);
+            a
+        })
+    }
+
+    ///call `visitor` with the indices of every intersecting pair, until it breaks
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
         if let Some(root) = self.nodes.last() {
-            self.recursive_visit(root.left, root.right, &mut output);
+            self.recursive_visit(root.left, root.right, &mut visitor)?;
         }
-        output
+        ControlFlow::Continue(())
+    }
+
+    ///same as `visit_collisions`, stopping like `Broadphase::visit_collisions_par`
+    pub fn visit_collisions_par<B: Send>(&self, visitor: impl Fn(usize, usize) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+        self.nodes.par_iter().try_for_each(|node| {
+            self.recursive_collision_between_nodes(node.left, node.right, &mut |a, b| visitor(a, b))
+        })
     }
 
-    fn recursive_visit(&self, left: NodeIndex, right: NodeIndex, output: &mut usize) {
-        self.recursive_collision_between_nodes(left, right, output);
+    fn recursive_visit<B>(&self, left: NodeIndex, right: NodeIndex, output: &mut impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.recursive_collision_between_nodes(left, right, output)?;
         if !left.is_leaf() {
             let node = &self.nodes[left.index()];
-            self.recursive_visit(node.left, node.right, output);
+            self.recursive_visit(node.left, node.right, output)?;
         }
         if !right.is_leaf() {
             let node = &self.nodes[right.index()];
-            self.recursive_visit(node.left, node.right, output);
+            self.recursive_visit(node.left, node.right, output)?;
         }
+        ControlFlow::Continue(())
     }
 
-    fn recursive_collision_between_nodes(&self, left: NodeIndex, right: NodeIndex, output: &mut usize) {
+    fn recursive_collision_between_nodes<B>(&self, left: NodeIndex, right: NodeIndex, output: &mut impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
         match (left.is_leaf(), right.is_leaf()) {
             (true, true) => {
                 let left_aabb = &self.leaves[left.index()].aabb;
                 let right_aabb = &self.leaves[right.index()].aabb;
                 if left_aabb.intersects(right_aabb) {
-                    *output += 1;
+                    output(left.index(), right.index())?;
                 }
             },
             (true, false) => {
@@ -181,8 +238,8 @@ impl BVH {
                 let left_aabb = &self.leaves[left.index()].aabb;
                 let right_aabb = &right_node.aabb;
                 if left_aabb.intersects(right_aabb) {
-                    self.recursive_collision_between_nodes(left, right_node.left, output);
-                    self.recursive_collision_between_nodes(left, right_node.right, output);
+                    self.recursive_collision_between_nodes(left, right_node.left, output)?;
+                    self.recursive_collision_between_nodes(left, right_node.right, output)?;
                 }
             },
             (false, true) => {
@@ -190,30 +247,132 @@ impl BVH {
                 let left_aabb = &left_node.aabb;
                 let right_aabb = &self.leaves[right.index()].aabb;
                 if left_aabb.intersects(right_aabb) {
-                    self.recursive_collision_between_nodes(left_node.left, right, output);
-                    self.recursive_collision_between_nodes(left_node.right, right, output);
+                    self.recursive_collision_between_nodes(left_node.left, right, output)?;
+                    self.recursive_collision_between_nodes(left_node.right, right, output)?;
                 }
             },
             (false, false) => {
                 let left_node = &self.nodes[left.index()];
                 let right_node = &self.nodes[right.index()];
                 if left_node.aabb.intersects(&right_node.aabb) {
-                    self.recursive_collision_between_nodes(left_node.left, right_node.left, output);
-                    self.recursive_collision_between_nodes(left_node.left, right_node.right, output);
-                    self.recursive_collision_between_nodes(left_node.right, right_node.left, output);
-                    self.recursive_collision_between_nodes(left_node.right, right_node.right, output);
+                    self.recursive_collision_between_nodes(left_node.left, right_node.left, output)?;
+                    self.recursive_collision_between_nodes(left_node.left, right_node.right, output)?;
+                    self.recursive_collision_between_nodes(left_node.right, right_node.left, output)?;
+                    self.recursive_collision_between_nodes(left_node.right, right_node.right, output)?;
                 }
             }
         }
+        ControlFlow::Continue(())
+    }
+
+    ///user data given to `build_with_payload` for the entity at this index
+    pub fn payload(&self, index: usize) -> &T {
+        &self.payloads[index]
+    }
+
+    ///raw `NodeIndex` of the nodes from the root down to the leaf of this entity, the ids of the `Hierarchy` impl
+    pub fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        if entity >= self.leaves.len() {
+            return None;
+        }
+        let mut current = NodeIndex::new_leaf(entity);
+        let mut path = vec![current.0 as usize];
+        while let Some(parent) = self.nodes.iter().position(|node| node.left.0 == current.0 || node.right.0 == current.0) {
+            current = NodeIndex::new_node(parent);
+            path.push(current.0 as usize);
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    ///same as `visit_collisions`, but hands back the payloads of the two entities
+    pub fn visit_collision_payloads<B>(&self, mut visitor: impl FnMut(&T, &T) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.visit_collisions(|a, b| visitor(&self.payloads[a], &self.payloads[b]))
+    }
+
+    pub fn visit_collision_payloads_par<B: Send>(&self, visitor: impl Fn(&T, &T) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+        self.visit_collisions_par(|a, b| visitor(&self.payloads[a], &self.payloads[b]))
+    }
+
+
+}
+
+///nodes are numbered by their `NodeIndex`, so a leaf is the index of its entity and a branch has the first bit set
+impl<T: Send + Sync> Hierarchy for BVH<T> {
+    fn root(&self) -> Option<usize> {
+        match self.nodes.len() {
+            0 => (self.leaves.len() == 1).then_some(NodeIndex::new_leaf(0).0 as usize),
+            len => Some(NodeIndex::new_node(len - 1).0 as usize), //the root is the last branch pushed
+        }
     }
 
+    fn node_aabb(&self, node: usize) -> &AABB {
+        self.get_aabb(NodeIndex(node as u32))
+    }
 
+    fn children(&self, node: usize) -> Option<(usize, usize)> {
+        let node = NodeIndex(node as u32);
+        if node.is_leaf() {
+            return None;
+        }
+        let node = &self.nodes[node.index()];
+        Some((node.left.0 as usize, node.right.0 as usize))
+    }
+
+    fn entity(&self, leaf: usize) -> usize {
+        NodeIndex(leaf as u32).index()
+    }
+}
+
+impl Broadphase for BVH {
+    fn name(&self) -> &'static str {
+        "bvh3"
+    }
+
+    fn build(&mut self, leaves: Vec<AABB>) {
+        BVH::build(self, leaves);
+    }
+
+    fn get_collision(&self) -> usize {
+        BVH::get_collision(self)
+    }
+
+    fn get_collision_par(&self) -> usize {
+        BVH::get_collision_par(self)
+    }
+
+    fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        BVH::get_collision_pairs(self)
+    }
+
+    fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        BVH::get_collision_pairs_par(self)
+    }
+
+    fn visit_collisions(&self, visitor: &mut dyn FnMut(usize, usize) -> ControlFlow<()>) -> ControlFlow<()> {
+        BVH::visit_collisions(self, visitor)
+    }
+
+    fn visit_collisions_par(&self, visitor: &(dyn Fn(usize, usize) -> ControlFlow<()> + Sync)) -> ControlFlow<()> {
+        BVH::visit_collisions_par(self, visitor)
+    }
+
+    fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        query_aabb(self, region)
+    }
+
+    fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        BVH::leaf_path(self, entity)
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::brute_force::testing::{random_scene, LEAF_COUNTS};
 
     #[test]
     fn test_node_index() {
@@ -227,4 +386,19 @@ mod tests {
         assert!(node.is_node());
         assert_eq!(node.index(), 5498);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn leaf_path_follows_the_hierarchy() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut tree = BVH::new();
+        for &count in LEAF_COUNTS.iter().filter(|&&count| count > 0) {
+            tree.build(random_scene(&mut rng, count));
+            for entity in 0..count {
+                let path = tree.leaf_path(entity).unwrap();
+                assert_eq!(path.first().copied(), tree.root(), "{count} leaves");
+                assert!(path.windows(2).all(|step| tree.children(step[0]).is_some_and(|(left, right)| step[1] == left || step[1] == right)), "{count} leaves");
+                assert_eq!(tree.entity(*path.last().unwrap()), entity, "{count} leaves");
+            }
+        }
+    }
+}
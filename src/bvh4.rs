@@ -1,40 +1,100 @@
+use std::ops::ControlFlow;
 use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator, IndexedParallelIterator};
 use rayon::slice::ParallelSliceMut;
+use crate::broadphase::{collector, counter, Broadphase};
+use crate::coherence::HilbertOrder;
+use fixed::types::I64F64;
+use crate::hierarchy::{query_aabb, Hierarchy};
 use crate::morton::to_hilbert;
 use crate::position::{AABB, EntityPosExt};
+use crate::sah;
 
-enum NodeKind {
-    Leaf,
+pub(crate) enum NodeKind {
+    Leaf(usize), //index of the entity in the vec given to build, leaves are reordered by their hilbert code
     Branch(usize, usize),
 }
 
-struct Node {
-    aabb: AABB,
-    kind: NodeKind,
+pub(crate) struct Node {
+    pub(crate) aabb: AABB,
+    pub(crate) kind: NodeKind,
 }
 
-pub struct BVH {
+///how `Broadphase::build` orders the leaves, the inherent methods of each builder can be called whatever the setting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Builder {
+    ///leaves sorted by hilbert code then paired level by level, the fastest to build
+    #[default]
+    Hilbert,
+    ///top down splits minimizing the surface area heuristic, slower to build but better for clustered or mixed size scenes
+    BinnedSah,
+}
+
+pub struct BVH<T = ()> {
     nodes: Vec<Node>, //from my observation, storing branches and leaves in the same vec is faster than storing them in separate vecs, I believe it's because of the cache
     start_of_branches: usize, // the slice [0..start_of_branches] contains the leaves, the slice [start_of_branches..] contains the branches
+    payloads: Vec<T>, //indexed by the entity index of `NodeKind::Leaf`, storing them in the nodes would make every branch bigger
+    order: HilbertOrder, //only used by `rebuild_par`, so a build doesn't pay for keeping it
+    builder: Builder,
 }
 
 impl BVH {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn build(&mut self, leaves: Vec<AABB>) {
+        let len = leaves.len();
+        self.build_with_payload(leaves, vec![(); len]);
+    }
+
+    pub fn build_par(&mut self, leaves: Vec<AABB>) {
+        let len = leaves.len();
+        self.build_par_with_payload(leaves, vec![(); len]);
+    }
+
+    pub fn rebuild_par(&mut self, leaves: Vec<AABB>) {
+        let len = leaves.len();
+        self.rebuild_par_with_payload(leaves, vec![(); len]);
+    }
+
+    pub fn build_sah(&mut self, leaves: Vec<AABB>) {
+        let len = leaves.len();
+        self.build_sah_with_payload(leaves, vec![(); len]);
+    }
+}
+
+impl<T> Default for BVH<T> {
+    fn default() -> Self {
         Self {
             nodes: Vec::new(),
             start_of_branches: 0,
+            payloads: Vec::new(),
+            order: HilbertOrder::new(),
+            builder: Builder::Hilbert,
         }
     }
+}
+
+impl<T: Send + Sync> BVH<T> {
+    pub fn with_builder(builder: Builder) -> Self {
+        Self {
+            builder,
+            ..Self::default()
+        }
+    }
+
+    pub fn build_with_payload(&mut self, leaves: Vec<AABB>, payloads: Vec<T>) {
+        assert_eq!(leaves.len(), payloads.len());
+        self.payloads = payloads;
 
-    pub fn build(&mut self, leaves: Vec<AABB>) {
         let len = leaves.len();
         self.nodes.clear();
-        self.nodes.reserve(2 * len - 1);
+        self.nodes.reserve((2 * len).saturating_sub(1));
 
         let mut hilbert_indices = leaves.iter().enumerate().map(|(i, aabb)| (to_hilbert(aabb.center().block_pos()), i)).collect::<Vec<_>>();
-        hilbert_indices.sort_unstable_by_key(|(morton, _)| *morton);
+        hilbert_indices.sort_unstable(); //ties by index, the order of `HilbertOrder`
 
-        let iter = hilbert_indices.into_par_iter().map(|(_, i)| Node { aabb: leaves[i], kind: NodeKind::Leaf });
+        let iter = hilbert_indices.into_par_iter().map(|(_, i)| Node { aabb: leaves[i], kind: NodeKind::Leaf(i) });
 
         iter.collect_into_vec(&mut self.nodes);
 
@@ -62,16 +122,48 @@ impl BVH {
         }
     }
 
-    pub fn build_par(&mut self, leaves: Vec<AABB>) {
-        let len = leaves.len();
-        self.nodes.clear();
-        self.nodes.reserve(2 * len - 1);
+    pub fn build_par_with_payload(&mut self, leaves: Vec<AABB>, payloads: Vec<T>) {
+        assert_eq!(leaves.len(), payloads.len());
+        self.payloads = payloads;
 
         //sort unstable is a bunch of garbage, maybe there is another better way to do it, for BVH 5 I'll lik
         let mut hilbert_indices = leaves.par_iter().enumerate().map(|(i, aabb)| (to_hilbert(aabb.center().block_pos()), i)).collect::<Vec<_>>();
-        hilbert_indices.par_sort_unstable_by_key(|(morton, _)| *morton);
+        hilbert_indices.par_sort_unstable(); //ties by index, so `rebuild_par_with_payload` gives the same tree
+
+        self.build_par_from_order(&leaves, hilbert_indices.into_par_iter().map(|(_, i)| i));
+    }
+
+    ///same tree as `build_par_with_payload`, but the hilbert order of the previous rebuild is repaired instead of sorting again
+    ///much cheaper when the entities barely move between two calls, see `HilbertOrder::update`
+    pub fn rebuild_par_with_payload(&mut self, leaves: Vec<AABB>, payloads: Vec<T>) {
+        assert_eq!(leaves.len(), payloads.len());
+        self.payloads = payloads;
+
+        let mut order = std::mem::take(&mut self.order);
+        order.update(&leaves);
+        self.build_par_from_order(&leaves, order.order().par_iter().copied());
+        self.order = order;
+    }
+
+    ///same layout as the other builders, but the leaves are grouped by `sah::build_nodes` instead of following the hilbert curve
+    pub fn build_sah_with_payload(&mut self, leaves: Vec<AABB>, payloads: Vec<T>) {
+        assert_eq!(leaves.len(), payloads.len());
+        self.payloads = payloads;
+        self.nodes = sah::build_nodes(&leaves);
+        self.start_of_branches = leaves.len();
+    }
+
+    ///sum of the surface areas of the branches, to compare the trees of both builders
+    pub fn surface_area_cost(&self) -> I64F64 {
+        self.nodes[self.start_of_branches..].par_iter().map(|node| node.aabb.surface_area()).reduce(|| I64F64::ZERO, |a, b| a.saturating_add(b))
+    }
+
+    fn build_par_from_order(&mut self, leaves: &[AABB], order: impl IndexedParallelIterator<Item = usize>) {
+        let len = leaves.len();
+        self.nodes.clear();
+        self.nodes.reserve((2 * len).saturating_sub(1));
 
-        let iter = hilbert_indices.into_par_iter().map(|(_, i)| Node { aabb: leaves[i], kind: NodeKind::Leaf });
+        let iter = order.map(|i| Node { aabb: leaves[i], kind: NodeKind::Leaf(i) });
 
         iter.collect_into_vec(&mut self.nodes);
 
@@ -105,9 +197,8 @@ impl BVH {
     pub fn get_collision_recursive(&self) -> usize {
         let mut output = 0;
         if let Some(Node{kind: NodeKind::Branch(left, right), ..}) = &self.nodes.last() { // if None, there is no collision
-            self.recursive_visit(*left, *right, &mut output);                           // if Some but not a branch, there is only one leaf, so no collision
+            let _ = self.recursive_visit(*left, *right, &mut counter(&mut output));      // if Some but not a branch, there is only one leaf, so no collision
         }
-        self.recursive_visit(0, 1, &mut output);
         output
     }
 
@@ -116,44 +207,199 @@ impl BVH {
         slice.par_iter().map(|node| { //this could be batched more efficiently, but I'm too lazy to do it
             let mut output = 0;
             if let NodeKind::Branch(left, right) = &node.kind {
-                self.recursive_collision_between_nodes(*left, *right, &mut output);
+                let _ = self.recursive_collision_between_nodes(*left, *right, &mut counter(&mut output));
             }
             output
         }).sum()
     }
 
-    pub fn recursive_visit(&self, left: usize, right: usize, output: &mut usize) {
+    ///every intersecting pair, as indices in the vec given to `build`
+    pub fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(collector(&mut pairs));
+        pairs
+    }
+
+    pub fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        let slice = &self.nodes[self.start_of_branches..];
+        slice.par_iter().fold(Vec::new, |mut pairs, node| {
+            if let NodeKind::Branch(left, right) = &node.kind {
+                let _ = self.recursive_collision_between_nodes(*left, *right, &mut collector(&mut pairs));
+            }
+            pairs
+        }).reduce(Vec::new, |mut a, mut b| {
+            a.append(&mut b);
+            a
+        })
+    }
+
+    ///call `visitor` with the indices of every intersecting pair, until it breaks
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        if let Some(Node{kind: NodeKind::Branch(left, right), ..}) = &self.nodes.last() {
+            self.recursive_visit(*left, *right, &mut visitor)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    ///same as `visit_collisions`, stopping like `Broadphase::visit_collisions_par`
+    pub fn visit_collisions_par<B: Send>(&self, visitor: impl Fn(usize, usize) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+        let slice = &self.nodes[self.start_of_branches..];
+        slice.par_iter().try_for_each(|node| {
+            if let NodeKind::Branch(left, right) = &node.kind {
+                self.recursive_collision_between_nodes(*left, *right, &mut |a, b| visitor(a, b))?;
+            }
+            ControlFlow::Continue(())
+        })
+    }
+
+    pub fn recursive_visit<B>(&self, left: usize, right: usize, output: &mut impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
         if let NodeKind::Branch(left, right) = &self.nodes[left].kind {
-            self.recursive_visit(*left, *right, output);
+            self.recursive_visit(*left, *right, output)?;
         }
         if let NodeKind::Branch(left, right) = &self.nodes[right].kind {
-            self.recursive_visit(*left, *right, output);
+            self.recursive_visit(*left, *right, output)?;
         }
-        self.recursive_collision_between_nodes(left, right, output);
+        self.recursive_collision_between_nodes(left, right, output)
     }
     
-    pub fn recursive_collision_between_nodes(&self, left: usize, right: usize, output: &mut usize) {
+    ///`output` is called with the indices of the two entities, as given to `build`
+    pub fn recursive_collision_between_nodes<B>(&self, left: usize, right: usize, output: &mut impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
         let left_node = &self.nodes[left];
         let right_node = &self.nodes[right];
-        if !AABB::intersects(&left_node.aabb, &right_node.aabb) { return; }
+        if !AABB::intersects(&left_node.aabb, &right_node.aabb) { return ControlFlow::Continue(()); }
         match (&left_node.kind, &right_node.kind) {
-            (NodeKind::Leaf, NodeKind::Leaf) => {
-                *output += 1;
+            (NodeKind::Leaf(left_index), NodeKind::Leaf(right_index)) => {
+                output(*left_index, *right_index)?;
             },
-            (NodeKind::Branch(left_left, left_right), NodeKind::Leaf) => {
-                self.recursive_collision_between_nodes(*left_left, right, output);
-                self.recursive_collision_between_nodes(*left_right, right, output);
+            (NodeKind::Branch(left_left, left_right), NodeKind::Leaf(_)) => {
+                self.recursive_collision_between_nodes(*left_left, right, output)?;
+                self.recursive_collision_between_nodes(*left_right, right, output)?;
             },
-            (NodeKind::Leaf, NodeKind::Branch(right_left, right_right)) => {
-                self.recursive_collision_between_nodes(left, *right_left, output);
-                self.recursive_collision_between_nodes(left, *right_right, output);
+            (NodeKind::Leaf(_), NodeKind::Branch(right_left, right_right)) => {
+                self.recursive_collision_between_nodes(left, *right_left, output)?;
+                self.recursive_collision_between_nodes(left, *right_right, output)?;
             },
             (NodeKind::Branch(left_left, left_right), NodeKind::Branch(right_left, right_right)) => {
-                self.recursive_collision_between_nodes(*left_left, *right_left, output);
-                self.recursive_collision_between_nodes(*left_left, *right_right, output);
-                self.recursive_collision_between_nodes(*left_right, *right_left, output);
-                self.recursive_collision_between_nodes(*left_right, *right_right, output);
+                self.recursive_collision_between_nodes(*left_left, *right_left, output)?;
+                self.recursive_collision_between_nodes(*left_left, *right_right, output)?;
+                self.recursive_collision_between_nodes(*left_right, *right_left, output)?;
+                self.recursive_collision_between_nodes(*left_right, *right_right, output)?;
             }
         }
+        ControlFlow::Continue(())
     } 
-}
\ No newline at end of file
+
+    ///user data given to `build_with_payload` for the entity at this index
+    pub fn payload(&self, index: usize) -> &T {
+        &self.payloads[index]
+    }
+
+    ///nodes from the root down to the leaf of this entity, slow since nodes don't know their parent
+    pub fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        let mut current = self.nodes[..self.start_of_branches].iter().position(|node| matches!(node.kind, NodeKind::Leaf(index) if index == entity))?;
+        let mut path = vec![current];
+        while let Some(offset) = self.nodes[self.start_of_branches..].iter().position(|node| matches!(node.kind, NodeKind::Branch(left, right) if left == current || right == current)) {
+            current = self.start_of_branches + offset;
+            path.push(current);
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    ///same as `visit_collisions`, but hands back the payloads of the two entities
+    pub fn visit_collision_payloads<B>(&self, mut visitor: impl FnMut(&T, &T) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.visit_collisions(|a, b| visitor(&self.payloads[a], &self.payloads[b]))
+    }
+
+    pub fn visit_collision_payloads_par<B: Send>(&self, visitor: impl Fn(&T, &T) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+        self.visit_collisions_par(|a, b| visitor(&self.payloads[a], &self.payloads[b]))
+    }
+}
+
+impl<T: Send + Sync> Hierarchy for BVH<T> {
+    fn root(&self) -> Option<usize> {
+        self.nodes.len().checked_sub(1) //the root is the last node pushed
+    }
+
+    fn node_aabb(&self, node: usize) -> &AABB {
+        &self.nodes[node].aabb
+    }
+
+    fn children(&self, node: usize) -> Option<(usize, usize)> {
+        match self.nodes[node].kind {
+            NodeKind::Branch(left, right) => Some((left, right)),
+            NodeKind::Leaf(_) => None,
+        }
+    }
+
+    fn entity(&self, leaf: usize) -> usize {
+        match self.nodes[leaf].kind {
+            NodeKind::Leaf(index) => index,
+            NodeKind::Branch(..) => panic!("node {leaf} is a branch"),
+        }
+    }
+}
+
+impl Broadphase for BVH {
+    fn name(&self) -> &'static str {
+        match self.builder {
+            Builder::Hilbert => "bvh4",
+            Builder::BinnedSah => "bvh4-sah",
+        }
+    }
+
+    fn build(&mut self, leaves: Vec<AABB>) {
+        match self.builder {
+            Builder::Hilbert => BVH::build(self, leaves),
+            Builder::BinnedSah => BVH::build_sah(self, leaves),
+        }
+    }
+
+    ///the binned builder is already parallel
+    fn build_par(&mut self, leaves: Vec<AABB>) {
+        match self.builder {
+            Builder::Hilbert => BVH::build_par(self, leaves),
+            Builder::BinnedSah => BVH::build_sah(self, leaves),
+        }
+    }
+
+    ///the binned builder has nothing to reuse from the previous tick
+    fn rebuild(&mut self, leaves: Vec<AABB>) {
+        match self.builder {
+            Builder::Hilbert => BVH::rebuild_par(self, leaves),
+            Builder::BinnedSah => BVH::build_sah(self, leaves),
+        }
+    }
+
+    fn get_collision(&self) -> usize {
+        self.get_collision_recursive()
+    }
+
+    fn get_collision_par(&self) -> usize {
+        BVH::get_collision_par(self)
+    }
+
+    fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        BVH::get_collision_pairs(self)
+    }
+
+    fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        BVH::get_collision_pairs_par(self)
+    }
+
+    fn visit_collisions(&self, visitor: &mut dyn FnMut(usize, usize) -> ControlFlow<()>) -> ControlFlow<()> {
+        BVH::visit_collisions(self, visitor)
+    }
+
+    fn visit_collisions_par(&self, visitor: &(dyn Fn(usize, usize) -> ControlFlow<()> + Sync)) -> ControlFlow<()> {
+        BVH::visit_collisions_par(self, visitor)
+    }
+
+    fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        query_aabb(self, region)
+    }
+
+    fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        BVH::leaf_path(self, entity)
+    }
+}
@@ -1,18 +1,62 @@
-use std::mem::MaybeUninit;
+use std::convert::Infallible;
+use std::ops::ControlFlow;
 use rayon::iter::{ParallelIterator, IndexedParallelIterator, IntoParallelRefMutIterator, IntoParallelRefIterator, IntoParallelIterator};
 use rayon::slice::ParallelSliceMut;
+use crate::broadphase::{collector, counter, Broadphase};
+use crate::coherence::HilbertOrder;
+use crate::hierarchy::Hierarchy;
 use crate::morton::to_hilbert;
-use crate::position::{AABB, EntityPosExt};
+use fixed::types::{I32F32, I64F64};
+use crate::position::{AABB, EntityPos, EntityPosExt};
 
-pub struct BVH {
+pub struct BVH<T = ()> {
     nodes: Vec<AABB>,
+    indices: Vec<usize>, //for each leaf, its index in the vec given to build, kept outside of nodes so the traversal only touches AABBs
+    payloads: Vec<T>, //user data of every entity, in the order given to build, kept apart so it doesn't slow down the traversal
     leaf_count: usize,
+    built_cost: I64F64, //surface area of the branches right after the build, refits can only make it worse
+    margin: Option<I32F32>, //with a margin the leaf nodes hold fat boxes, and refits leave them alone while the entities stay inside
+    built_margin: Option<I32F32>, //margin of the last build, what the leaf nodes hold until the next one whatever `set_margin` says
+    tight: Vec<AABB>, //exact box of each leaf, in the same order as the leaf nodes, empty without a margin at the last build
+    order: HilbertOrder, //only used by `rebuild`, so a build doesn't pay for keeping it
     //using complete binary tree representation
     // since the root is the last node
     // the left child of a node at index i is at index 2*i + 1
 }
 
 impl BVH {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn build(&mut self, leaves: Vec<AABB>) {
+        let len = leaves.len();
+        self.build_with_payload(leaves, vec![(); len]);
+    }
+
+    pub fn rebuild(&mut self, leaves: Vec<AABB>) {
+        let len = leaves.len();
+        self.rebuild_with_payload(leaves, vec![(); len]);
+    }
+}
+
+impl<T> Default for BVH<T> {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            indices: Vec::new(),
+            leaf_count: 0,
+            built_cost: I64F64::ZERO,
+            margin: None,
+            built_margin: None,
+            tight: Vec::new(),
+            order: HilbertOrder::new(),
+            payloads: Vec::new(),
+        }
+    }
+}
+
+impl<T: Send + Sync> BVH<T> {
 
     #[inline]
     fn get_parent(index: usize) -> usize {
@@ -29,38 +73,64 @@ impl BVH {
         index >= (len >> 1)
     }
 
+    ///there is n - 1 branches for n leaves, and none for an empty tree
+    #[inline]
+    fn branch_count(&self) -> usize {
+        self.leaf_count.saturating_sub(1)
+    }
 
-    pub fn new() -> Self {
-        Self {
-            nodes: Vec::new(),
-            leaf_count: 0,
-        }
+    ///index in the vec given to build of the entity stored in this leaf node
+    #[inline]
+    fn entity_index(&self, leaf: usize) -> usize {
+        self.indices[leaf - self.branch_count()]
     }
 
-    fn post_fixe_node_build(array: &mut[MaybeUninit<AABB>], index: usize) -> AABB {
-        if Self::is_leaf(index, array.len()) {
-            let node = &array[index];
-            let aabb = unsafe { node.assume_init() };
-            return aabb;
-        }
-        let (left, right) = Self::get_childs(index);
-        let left_aabb = Self::post_fixe_node_build(array, left);
-        let right_aabb = Self::post_fixe_node_build(array, right);
-        let new_aabb = AABB::union(&left_aabb, &right_aabb);
-        array[index].write(new_aabb);
-        new_aabb
+    ///the box of the node, except for the leaves of a tree with a margin, where it's the exact box instead of the fat one
+    #[inline]
+    fn exact_aabb(&self, node: usize) -> &AABB {
+        let branch_count = self.branch_count();
+        if !self.tight.is_empty() && node >= branch_count { &self.tight[node - branch_count] } else { &self.nodes[node] }
     }
 
-    pub fn build(&mut self, leaves: Vec<AABB>) {
+    ///used from the next build, None (the default) stores the exact boxes in the tree, the refits keep using the margin of the last build
+    ///even a zero margin keeps the fat boxes apart, so `refit_moving` can stretch them
+    pub fn set_margin(&mut self, margin: Option<I32F32>) {
+        self.margin = margin;
+    }
+
+    pub fn build_with_payload(&mut self, leaves: Vec<AABB>, payloads: Vec<T>) {
+        assert_eq!(leaves.len(), payloads.len());
+        self.payloads = payloads;
 
         let mut hilbert_indices = leaves.par_iter().enumerate().map(|(i, aabb)| (to_hilbert(aabb.center().block_pos()), i)).collect::<Vec<_>>();
-        hilbert_indices.par_sort_unstable_by_key(|(morton, _)| *morton);
+        hilbert_indices.par_sort_unstable(); //ties by index, so `rebuild_with_payload` gives the same tree
+
+        hilbert_indices.into_par_iter().map(|(_, i)| i).collect_into_vec(&mut self.indices);
+        self.build_from_indices(&leaves);
+    }
 
+    ///same tree as `build_with_payload`, but the hilbert order of the previous rebuild is repaired instead of sorting again
+    pub fn rebuild_with_payload(&mut self, leaves: Vec<AABB>, payloads: Vec<T>) {
+        assert_eq!(leaves.len(), payloads.len());
+        self.payloads = payloads;
+
+        self.order.update(&leaves);
+        self.order.order().par_iter().copied().collect_into_vec(&mut self.indices);
+        self.build_from_indices(&leaves);
+    }
 
+    ///build the nodes once `indices` holds the entities in hilbert order
+    fn build_from_indices(&mut self, leaves: &[AABB]) {
         self.leaf_count = leaves.len();
+        self.built_margin = self.margin;
+        self.nodes.clear();
+        self.tight.clear();
+        if leaves.is_empty() {
+            self.built_cost = I64F64::ZERO;
+            return;
+        }
         let leaf_start = self.leaf_count - 1; //because the is n - 1 branches for n leaves
         let len = 2 * self.leaf_count - 1;
-        self.nodes.clear();
         self.nodes.reserve(len);
 
         //example for 11 leaves
@@ -73,13 +143,19 @@ impl BVH {
         let array = self.nodes.spare_capacity_mut();
         { //little scope where we likely put our mess
             let leafs = &mut array[leaf_start..len];
-            leafs.par_iter_mut().enumerate().for_each(|(i,uninit)|{
-                uninit.write(leaves[hilbert_indices[i].1]);
-            });
+            match self.built_margin {
+                None => leafs.par_iter_mut().zip(self.indices.par_iter()).for_each(|(uninit, &i)|{
+                    uninit.write(leaves[i]);
+                }),
+                Some(margin) => {
+                    self.indices.par_iter().map(|&i| leaves[i]).collect_into_vec(&mut self.tight);
+                    leafs.par_iter_mut().zip(self.tight.par_iter()).for_each(|(uninit, tight)| {
+                        uninit.write(tight.fattened(margin, &EntityPos::zeros()));
+                    });
+                }
+            }
         }
 
-        //Self::post_fixe_node_build(array, 0);
-
         for i in (0..leaf_start).rev() {
             let (left, right) = Self::get_childs(i);
             let left_aabb = &array[left];
@@ -91,55 +167,429 @@ impl BVH {
         }
 
         unsafe { self.nodes.set_len(len); } //BAM
+
+        self.built_cost = self.surface_area_cost();
+    }
+
+    ///move every entity to its new box without touching the shape of the tree, `leaves` is indexed like the vec given to build
+    ///much cheaper than a build since there is no sort, but the tree gets worse as the entities drift, see `degradation`
+    ///returns how many leaf nodes changed, with a margin only the entities leaving their fat box count, and the branches are left alone if there is none
+    pub fn refit(&mut self, leaves: &[AABB]) -> usize {
+        self.refit_moving(leaves, &[])
+    }
+
+    ///same as `refit`, the fat boxes rebuilt are also stretched by `displacements`, the expected moves of the next tick
+    ///`displacements` is indexed like `leaves`, it can be empty, and is ignored if the last build had no margin
+    pub fn refit_moving(&mut self, leaves: &[AABB], displacements: &[EntityPos]) -> usize {
+        assert_eq!(leaves.len(), self.leaf_count);
+        assert!(displacements.is_empty() || displacements.len() == self.leaf_count);
+        let branch_count = self.branch_count();
+        let leaf_nodes = self.nodes[branch_count..].par_iter_mut().zip(self.indices.par_iter());
+        let changed: usize = match self.built_margin {
+            None => leaf_nodes.map(|(node, &entity)| {
+                let moved = *node != leaves[entity];
+                *node = leaves[entity];
+                moved as usize
+            }).sum(),
+            Some(margin) => leaf_nodes.zip(self.tight.par_iter_mut()).map(|((node, &entity), tight)| {
+                *tight = leaves[entity];
+                if node.contains(tight) {
+                    return 0;
+                }
+                let displacement = displacements.get(entity).copied().unwrap_or_else(EntityPos::zeros);
+                *node = tight.fattened(margin, &displacement);
+                1
+            }).sum(),
+        };
+        if branch_count == 0 || changed == 0 {
+            return changed;
+        }
+
+        //the level at depth d holds the nodes 2^d - 1 to 2^(d+1) - 2, their children are all in the next level
+        //so each level is done in parallel, from the one holding the last branch up to the root
+        let deepest = branch_count.ilog2() as usize;
+        for depth in (0..=deepest).rev() {
+            let start = (1 << depth) - 1;
+            let end = ((1 << (depth + 1)) - 1).min(branch_count);
+            let (above, below) = self.nodes.split_at_mut(end);
+            above[start..].par_iter_mut().enumerate().for_each(|(i, node)| {
+                let (left, right) = Self::get_childs(start + i);
+                *node = below[left - end].union(&below[right - end]);
+            });
+        }
+        changed
+    }
+
+    ///sum of the surface areas of the branches, the expected cost of a traversal according to the surface area heuristic
+    pub fn surface_area_cost(&self) -> I64F64 {
+        self.nodes[..self.branch_count()].par_iter().map(AABB::surface_area).reduce(|| I64F64::ZERO, |a, b| a.saturating_add(b))
+    }
+
+    ///how much worse the tree is than right after the build, 1 means as good, 2 means traversals are expected to take twice as long
+    ///a rebuild is worth it once it gets past what a build costs compared to a refit, usually around 1.5
+    pub fn degradation(&self) -> f64 {
+        if self.built_cost == I64F64::ZERO {
+            return 1.0;
+        }
+        self.surface_area_cost().to_num::<f64>() / self.built_cost.to_num::<f64>()
+    }
+
+    pub fn get_collision(&self) -> usize {
+        let mut output = 0;
+        for i in 0..self.branch_count() { //every branch checks the collisions between its two children
+            let (left, right) = Self::get_childs(i);
+            let _ = self.recursive_collision_between_nodes(left, right, &mut counter(&mut output));
+        }
+        output
     }
 
     pub fn get_collision_par(&self) -> usize {
-        let slice = 0..self.leaf_count - 1;
+        let slice = 0..self.branch_count();
         slice.into_par_iter().map(|i| { //the simple presence of rev() cut the time by half, cache coherence is my guess
             let mut output = 0;
             let (left, right) = Self::get_childs(i);
-            self.recursive_collision_between_nodes(left, right, &mut output);
+            let _ = self.recursive_collision_between_nodes(left, right, &mut counter(&mut output));
             output
         }).sum()
     }
     
     pub fn get_collision_rev_par(&self) -> usize {
-        let slice = 0..self.leaf_count - 1;
+        let slice = 0..self.branch_count();
         slice.into_par_iter().rev().map(|i| { //the simple presence of rev() cut the time by half, cache coherence is my guess
             let mut output = 0;
             let (left, right) = Self::get_childs(i);
-            self.recursive_collision_between_nodes(left, right, &mut output);
+            let _ = self.recursive_collision_between_nodes(left, right, &mut counter(&mut output));
             output
         }).sum() 
     }
 
-    pub fn recursive_collision_between_nodes(&self, left: usize, right: usize, output: &mut usize) {
+    ///every intersecting pair, as indices in the vec given to `build`
+    pub fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(collector(&mut pairs));
+        pairs
+    }
+
+    pub fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        let slice = 0..self.branch_count();
+        slice.into_par_iter().fold(Vec::new, |mut pairs, i| {
+            let (left, right) = Self::get_childs(i);
+            let _ = self.recursive_collision_between_nodes(left, right, &mut |a, b| {
+                pairs.push((self.entity_index(a), self.entity_index(b)));
+                ControlFlow::<Infallible>::Continue(())
+            });
+            pairs
+        }).reduce(Vec::new, |mut a, mut b| {
+            a.append(&mut b);
+            a
+        })
+    }
+
+    ///call `visitor` with the indices in the vec given to `build` of every intersecting pair, until it breaks
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        for i in 0..self.branch_count() {
+            let (left, right) = Self::get_childs(i);
+            self.recursive_collision_between_nodes(left, right, &mut |a, b| visitor(self.entity_index(a), self.entity_index(b)))?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    ///same as `visit_collisions`, stopping like `Broadphase::visit_collisions_par`
+    pub fn visit_collisions_par<B: Send>(&self, visitor: impl Fn(usize, usize) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+        let slice = 0..self.branch_count();
+        slice.into_par_iter().try_for_each(|i| {
+            let (left, right) = Self::get_childs(i);
+            self.recursive_collision_between_nodes(left, right, &mut |a, b| visitor(self.entity_index(a), self.entity_index(b)))
+        })
+    }
+
+    ///`output` is called with the node indices of the two leaves, `entity_index` gives back the index in the vec given to build
+    ///we don't translate them here, so counting collisions doesn't pay for the lookup
+    pub fn recursive_collision_between_nodes<B>(&self, left: usize, right: usize, output: &mut impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
         let len = self.nodes.len();
         let left_node = &self.nodes[left];
         let right_node = &self.nodes[right];
-        if !AABB::intersects(left_node, right_node) { return; }
+        if !AABB::intersects(left_node, right_node) { return ControlFlow::Continue(()); }
         match (Self::is_leaf(left, len), Self::is_leaf(right, len)) {
             (true, true) => {
-                *output += 1;
+                //the fat boxes overlapping isn't enough with a margin
+                if self.tight.is_empty() || self.exact_aabb(left).intersects(self.exact_aabb(right)) {
+                    output(left, right)?;
+                }
             },
             (false, true) => {
                 let (left_left, left_right) = Self::get_childs(left);
-                self.recursive_collision_between_nodes(left_left, right, output);
-                self.recursive_collision_between_nodes(left_right, right, output);
+                self.recursive_collision_between_nodes(left_left, right, output)?;
+                self.recursive_collision_between_nodes(left_right, right, output)?;
             },
             (true, false) => {
                 let (right_left, right_right) = Self::get_childs(right);
-                self.recursive_collision_between_nodes(left, right_left, output);
-                self.recursive_collision_between_nodes(left, right_right, output);
+                self.recursive_collision_between_nodes(left, right_left, output)?;
+                self.recursive_collision_between_nodes(left, right_right, output)?;
             },
             (false, false) => {
                 let (left_left, left_right) = Self::get_childs(left);
                 let (right_left, right_right) = Self::get_childs(right);
-                self.recursive_collision_between_nodes(left_left, right_left, output);
-                self.recursive_collision_between_nodes(left_left, right_right, output);
-                self.recursive_collision_between_nodes(left_right, right_left, output);
-                self.recursive_collision_between_nodes(left_right, right_right, output);
+                self.recursive_collision_between_nodes(left_left, right_left, output)?;
+                self.recursive_collision_between_nodes(left_left, right_right, output)?;
+                self.recursive_collision_between_nodes(left_right, right_left, output)?;
+                self.recursive_collision_between_nodes(left_right, right_right, output)?;
             }
         }
+        ControlFlow::Continue(())
+    }
+
+    ///every entity whose box overlaps `region`, as indices in the vec given to `build`, in tree order
+    pub fn query_aabb(&self, region: &AABB) -> AabbQuery<'_, T> {
+        let stack = if self.nodes.is_empty() { Vec::new() } else { vec![0] };
+        AabbQuery {
+            bvh: self,
+            region: *region,
+            stack,
+        }
     }
-}
\ No newline at end of file
+
+    ///same as `query_aabb`, calling `visitor` with each entity until it breaks
+    pub fn visit_aabb<B>(&self, region: &AABB, mut visitor: impl FnMut(usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        if self.nodes.is_empty() {
+            return ControlFlow::Continue(());
+        }
+        self.recursive_query_aabb(0, region, &mut visitor)
+    }
+
+    fn recursive_query_aabb<B>(&self, node: usize, region: &AABB, output: &mut impl FnMut(usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        if !self.exact_aabb(node).intersects(region) { return ControlFlow::Continue(()); }
+        if Self::is_leaf(node, self.nodes.len()) {
+            return output(self.entity_index(node));
+        }
+        let (left, right) = Self::get_childs(node);
+        self.recursive_query_aabb(left, region, output)?;
+        self.recursive_query_aabb(right, region, output)
+    }
+
+    ///user data given to `build_with_payload` for the entity at this index
+    pub fn payload(&self, index: usize) -> &T {
+        &self.payloads[index]
+    }
+
+    ///nodes from the root down to the leaf of this entity, the parents are implicit but we still have to look for the leaf
+    pub fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        let mut current = self.branch_count() + self.indices.iter().position(|index| *index == entity)?;
+        let mut path = vec![current];
+        while current != 0 {
+            current = Self::get_parent(current);
+            path.push(current);
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    ///same as `visit_collisions`, but hands back the payloads of the two entities
+    pub fn visit_collision_payloads<B>(&self, mut visitor: impl FnMut(&T, &T) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.visit_collisions(|a, b| visitor(&self.payloads[a], &self.payloads[b]))
+    }
+
+    pub fn visit_collision_payloads_par<B: Send>(&self, visitor: impl Fn(&T, &T) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+        self.visit_collisions_par(|a, b| visitor(&self.payloads[a], &self.payloads[b]))
+    }
+}
+
+///iterator returned by `BVH::query_aabb`, a depth first walk keeping the nodes left to visit on a stack
+pub struct AabbQuery<'a, T> {
+    bvh: &'a BVH<T>,
+    region: AABB,
+    stack: Vec<usize>,
+}
+
+impl<T: Send + Sync> Iterator for AabbQuery<'_, T> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while let Some(node) = self.stack.pop() {
+            if !self.bvh.exact_aabb(node).intersects(&self.region) { continue; }
+            if BVH::<T>::is_leaf(node, self.bvh.nodes.len()) {
+                return Some(self.bvh.entity_index(node));
+            }
+            let (left, right) = BVH::<T>::get_childs(node);
+            self.stack.push(right); //pushed first so the left child is visited first, like `visit_aabb`
+            self.stack.push(left);
+        }
+        None
+    }
+}
+
+impl<T: Send + Sync> Hierarchy for BVH<T> {
+    fn root(&self) -> Option<usize> {
+        (!self.nodes.is_empty()).then_some(0)
+    }
+
+    fn node_aabb(&self, node: usize) -> &AABB {
+        self.exact_aabb(node)
+    }
+
+    fn children(&self, node: usize) -> Option<(usize, usize)> {
+        (!Self::is_leaf(node, self.nodes.len())).then(|| Self::get_childs(node))
+    }
+
+    fn entity(&self, leaf: usize) -> usize {
+        self.entity_index(leaf)
+    }
+}
+
+impl Broadphase for BVH {
+    fn name(&self) -> &'static str {
+        "bvh5"
+    }
+
+    fn build(&mut self, leaves: Vec<AABB>) {
+        BVH::build(self, leaves);
+    }
+
+    fn rebuild(&mut self, leaves: Vec<AABB>) {
+        BVH::rebuild(self, leaves);
+    }
+
+    fn get_collision(&self) -> usize {
+        BVH::get_collision(self)
+    }
+
+    fn get_collision_par(&self) -> usize {
+        BVH::get_collision_par(self)
+    }
+
+    fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        BVH::get_collision_pairs(self)
+    }
+
+    fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        BVH::get_collision_pairs_par(self)
+    }
+
+    fn visit_collisions(&self, visitor: &mut dyn FnMut(usize, usize) -> ControlFlow<()>) -> ControlFlow<()> {
+        BVH::visit_collisions(self, visitor)
+    }
+
+    fn visit_collisions_par(&self, visitor: &(dyn Fn(usize, usize) -> ControlFlow<()> + Sync)) -> ControlFlow<()> {
+        BVH::visit_collisions_par(self, visitor)
+    }
+
+    fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        BVH::query_aabb(self, region).collect()
+    }
+
+    fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        BVH::leaf_path(self, entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::brute_force::testing::{random_displacement, random_scene, LEAF_COUNTS, SEEDS};
+    use crate::brute_force::{normalize_pairs, BruteForce};
+    use crate::bvh4;
+    use crate::hierarchy::query_aabb;
+    use crate::position::new_fixed_vec;
+
+    #[test]
+    fn refit_keeps_the_margin_of_the_build() {
+        let apart = vec![AABB::from_center(new_fixed_vec(0, 0, 0), new_fixed_vec(1, 1, 1)), AABB::from_center(new_fixed_vec(10, 0, 0), new_fixed_vec(1, 1, 1))];
+        let touching = vec![apart[0], AABB::from_center(new_fixed_vec(1, 0, 0), new_fixed_vec(1, 1, 1))];
+        let mut oracle = BruteForce::new();
+        oracle.build(touching.clone());
+        let expected = normalize_pairs(oracle.get_collision_pairs());
+        assert_eq!(expected, vec![(0, 1)]);
+        for (built, refitted) in [(None, Some(I32F32::ONE)), (Some(I32F32::ONE), None)] {
+            let mut bvh = BVH::new();
+            bvh.set_margin(built);
+            bvh.build(apart.clone());
+            bvh.set_margin(refitted);
+            assert!(bvh.refit(&touching) > 0, "margin {built:?} then {refitted:?}");
+            assert_eq!(normalize_pairs(bvh.get_collision_pairs()), expected, "margin {built:?} then {refitted:?}");
+            assert_eq!(normalize_pairs(bvh.get_collision_pairs_par()), expected, "margin {built:?} then {refitted:?}");
+        }
+    }
+
+    #[test]
+    fn region_query_matches_oracle() {
+        let mut structure = BVH::new();
+        let mut bvh4 = bvh4::BVH::new();
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for count in LEAF_COUNTS {
+                let leaves = random_scene(&mut rng, count);
+                let regions = random_scene(&mut rng, 8);
+                let mut oracle = BruteForce::new();
+                oracle.build(leaves.clone());
+                bvh4.build(leaves.clone());
+                structure.build(leaves);
+                for region in regions {
+                    let expected = oracle.query_aabb(&region);
+                    let mut found: Vec<usize> = structure.query_aabb(&region).collect();
+                    found.sort_unstable();
+                    assert_eq!(found, expected, "wrong entities with seed {seed} and {count} leaves");
+                    assert_eq!(query_aabb(&structure, &region), structure.query_aabb(&region).collect::<Vec<_>>(), "the generic walk should match");
+                    let mut found = query_aabb(&bvh4, &region);
+                    found.sort_unstable();
+                    assert_eq!(found, expected, "bvh4: wrong entities with seed {seed} and {count} leaves");
+
+                    let mut visited = Vec::new();
+                    let _ = structure.visit_aabb(&region, |entity| {
+                        visited.push(entity);
+                        ControlFlow::<()>::Continue(())
+                    });
+                    assert_eq!(visited, structure.query_aabb(&region).collect::<Vec<_>>(), "both forms should walk the tree in the same order");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn refit_matches_oracle() {
+        for margin in [None, Some(I32F32::ZERO), Some(I32F32::from_num(2))] {
+            let mut structure = BVH::new();
+            structure.set_margin(margin);
+            for seed in 0..SEEDS {
+                let mut rng = StdRng::seed_from_u64(seed);
+                for count in LEAF_COUNTS {
+                    let leaves = random_scene(&mut rng, count);
+                    structure.build(leaves.clone());
+                    assert_eq!(structure.refit(&leaves), 0, "refitting the same boxes changes nothing");
+                    assert_eq!(structure.degradation(), 1.0, "refitting the same boxes changes nothing");
+
+                    let displacements: Vec<EntityPos> = (0..count).map(|_| random_displacement(&mut rng)).collect();
+                    let moved: Vec<AABB> = leaves.iter().zip(&displacements).map(|(aabb, displacement)| {
+                        AABB::new(aabb.min() + displacement, aabb.max() + displacement)
+                    }).collect();
+                    let context = format!("seed {seed}, {count} leaves and margin {margin:?}");
+                    if rng.gen_bool(0.5) {
+                        structure.refit(&moved);
+                    } else {
+                        structure.refit_moving(&moved, &displacements);
+                    }
+                    let mut oracle = BruteForce::new();
+                    oracle.build(moved.clone());
+                    let expected = oracle.get_collision_pairs();
+                    assert_eq!(normalize_pairs(structure.get_collision_pairs()), expected, "wrong pairs after refit with {context}");
+                    assert_eq!(normalize_pairs(structure.get_collision_pairs_par()), expected, "wrong par pairs after refit with {context}");
+                    let region = random_scene(&mut rng, 1)[0];
+                    let mut found: Vec<usize> = structure.query_aabb(&region).collect();
+                    found.sort_unstable();
+                    assert_eq!(found, oracle.query_aabb(&region), "wrong region query after refit with {context}");
+
+                    //moving everything by less than the margin keeps every fat box
+                    let nudged: Vec<AABB> = moved.iter().map(|aabb| AABB::new(aabb.min() + new_fixed_vec(0.5, 0, -1), aabb.max() + new_fixed_vec(0.5, 0, -1))).collect();
+                    let changed = structure.refit(&nudged);
+                    if margin == Some(I32F32::from_num(2)) {
+                        assert_eq!(changed, 0, "fat boxes rebuilt with {context}");
+                    }
+                    let mut oracle = BruteForce::new();
+                    oracle.build(nudged);
+                    assert_eq!(normalize_pairs(structure.get_collision_pairs()), oracle.get_collision_pairs(), "wrong pairs after a nudge with {context}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use rayon::prelude::*;
+use crate::morton::to_hilbert;
+use crate::position::{BlockPos, EntityPosExt, AABB};
+
+///above this share of moved entities, sorting everything again is cheaper than the merge
+const MAX_MOVED_RATIO: usize = 4;
+
+///entities sorted by the hilbert code of their center, kept between ticks so the next sort starts from the previous order
+///between two ticks most entities stay in the same block, their codes aren't computed again and their order is still right,
+///so only the moved ones are sorted, then merged back in
+#[derive(Default)]
+pub struct HilbertOrder {
+    blocks: Vec<BlockPos>, //block of the center of each entity, a code only changes when its block does
+    codes: Vec<u128>, //indexed by entity
+    order: Vec<usize>, //entities sorted by code, ties by index so both ways to sort agree
+    changed: Vec<bool>, //buffers reused from one tick to the next
+    moved: Vec<(u128, usize)>,
+    kept: Vec<usize>,
+}
+
+impl HilbertOrder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///indices of the entities in hilbert order
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    ///compute every code and sort from scratch, what the builds of `bvh4` and `bvh5` do
+    pub fn sort(&mut self, leaves: &[AABB]) {
+        leaves.par_iter().map(|aabb| aabb.center().block_pos()).collect_into_vec(&mut self.blocks);
+        self.blocks.par_iter().map(|block| to_hilbert(*block)).collect_into_vec(&mut self.codes);
+        (0..leaves.len()).into_par_iter().collect_into_vec(&mut self.order);
+        self.changed.clear();
+        self.changed.resize(leaves.len(), false);
+        let codes = &self.codes;
+        self.order.par_sort_unstable_by_key(|&i| (codes[i], i));
+    }
+
+    ///same order as `sort`, starting from the one of the previous call, returns how many entities changed block
+    ///falls back to `sort` when the entity count changed or too many entities moved
+    pub fn update(&mut self, leaves: &[AABB]) -> usize {
+        if leaves.len() != self.order.len() {
+            self.sort(leaves);
+            return leaves.len();
+        }
+
+        let moved_count: usize = self.blocks.par_iter_mut().zip(self.codes.par_iter_mut()).zip(self.changed.par_iter_mut()).zip(leaves.par_iter())
+            .map(|(((block, code), changed), aabb)| {
+                let new_block = aabb.center().block_pos();
+                *changed = new_block != *block;
+                if *changed {
+                    *block = new_block;
+                    *code = to_hilbert(new_block);
+                }
+                *changed as usize
+            }).sum();
+        if moved_count == 0 {
+            return 0;
+        }
+        let codes = &self.codes;
+        if moved_count * MAX_MOVED_RATIO > leaves.len() {
+            self.order.par_sort_by_key(|&i| (codes[i], i)); //the stable sort still takes advantage of the sorted runs
+            return moved_count;
+        }
+
+        //the codes of the other entities didn't change, so they are still sorted, only the moved ones are sorted again
+        self.kept.clear();
+        self.moved.clear();
+        for &entity in &self.order {
+            if self.changed[entity] {
+                self.moved.push((codes[entity], entity));
+            } else {
+                self.kept.push(entity);
+            }
+        }
+        self.moved.par_sort_unstable();
+
+        let (mut kept, mut moved) = (self.kept.iter().peekable(), self.moved.iter().peekable());
+        for slot in self.order.iter_mut() {
+            let take_kept = match (kept.peek(), moved.peek()) {
+                (Some(&&entity), Some(&&key)) => (codes[entity], entity) < key,
+                (Some(_), None) => true,
+                _ => false,
+            };
+            *slot = if take_kept { *kept.next().unwrap() } else { moved.next().unwrap().1 };
+        }
+        moved_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::broadphase::Broadphase;
+    use crate::brute_force::testing::{random_displacement, random_scene, LEAF_COUNTS, SEEDS};
+    use crate::brute_force::{normalize_pairs, BruteForce};
+    use crate::position::{new_fixed_vec, EntityPos};
+    use crate::{bvh4, bvh5};
+
+    #[test]
+    fn update_matches_a_full_sort() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut leaves: Vec<AABB> = (0..2000).map(|_| {
+            let center: EntityPos = new_fixed_vec(rng.gen_range(-100..100), rng.gen_range(-100..100), rng.gen_range(-100..100));
+            AABB::from_center(center, new_fixed_vec(1, 1, 1))
+        }).collect();
+        let mut coherent = HilbertOrder::new();
+        let mut full = HilbertOrder::new();
+        for tick in 0..20 {
+            //a few ticks move most of the entities, to go through the fallback too
+            let probability = if tick % 7 == 6 { 0.9 } else { 0.05 };
+            for aabb in leaves.iter_mut() {
+                if rng.gen_bool(probability) {
+                    let displacement: EntityPos = new_fixed_vec(rng.gen_range(-3.0..3.0), rng.gen_range(-3.0..3.0), rng.gen_range(-3.0..3.0));
+                    *aabb = AABB::new(aabb.min() + displacement, aabb.max() + displacement);
+                }
+            }
+            if tick == 10 {
+                leaves.truncate(1500);
+            }
+            coherent.update(&leaves);
+            full.sort(&leaves);
+            assert_eq!(coherent.order(), full.order(), "tick {tick}");
+        }
+        assert_eq!(coherent.update(&leaves), 0);
+    }
+
+    #[test]
+    fn rebuilds_match_oracle() {
+        //the same tree as a build means the pairs come out in the same order
+        let structures: [fn() -> Box<dyn Broadphase>; 2] = [|| Box::new(bvh4::BVH::new()), || Box::new(bvh5::BVH::new())];
+        for fresh in structures {
+            let mut structure = fresh();
+            let name = structure.name();
+            for seed in 0..SEEDS {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let count = LEAF_COUNTS[seed as usize % LEAF_COUNTS.len()];
+                let mut leaves = random_scene(&mut rng, count);
+                for tick in 0..4 {
+                    structure.rebuild(leaves.clone());
+                    let mut oracle = BruteForce::new();
+                    oracle.build(leaves.clone());
+                    let expected = oracle.get_collision_pairs();
+                    assert_eq!(normalize_pairs(structure.get_collision_pairs()), expected, "{name}: wrong pairs with seed {seed}, {count} leaves, tick {tick}");
+                    assert_eq!(normalize_pairs(structure.get_collision_pairs_par()), expected, "{name}: wrong par pairs with seed {seed}, {count} leaves, tick {tick}");
+                    let mut built = fresh();
+                    built.build_par(leaves.clone());
+                    assert_eq!(structure.get_collision_pairs(), built.get_collision_pairs(), "{name}: not the tree of a build with seed {seed}, {count} leaves, tick {tick}");
+                    for aabb in leaves.iter_mut() {
+                        let displacement = random_displacement(&mut rng);
+                        *aabb = AABB::new(aabb.min() + displacement, aabb.max() + displacement);
+                    }
+                }
+            }
+        }
+    }
+}
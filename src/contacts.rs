@@ -0,0 +1,317 @@
+use std::cmp::Ordering;
+use std::convert::Infallible;
+use std::ops::ControlFlow;
+use rayon::prelude::*;
+use crate::bipartite::recursive_cross_collision;
+use crate::broadphase::TASKS_PER_THREAD;
+use crate::hierarchy::Hierarchy;
+
+///what happened to a pair of entities since the previous tick, the smallest index always comes first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContactEvent {
+    Begin(usize, usize),
+    Persist(usize, usize),
+    End(usize, usize),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventCounts {
+    pub began: usize,
+    pub persisted: usize,
+    pub ended: usize,
+}
+
+///overlapping pairs of the last two ticks, to turn the pair output of any structure into begin, persist and end events
+///the pairs are packed in sorted lists of u64, so 100M pairs take 800MB per tick where a HashSet would take several times that
+///the indices must fit in 32 bits, a bigger one panics
+#[derive(Default)]
+pub struct PairCache {
+    previous: Vec<u64>,
+    current: Vec<u64>,
+}
+
+impl PairCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///number of pairs overlapping this tick
+    pub fn len(&self) -> usize {
+        self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.current.is_empty()
+    }
+
+    ///forget both ticks, every pair of the next update begins
+    pub fn clear(&mut self) {
+        self.previous.clear();
+        self.current.clear();
+    }
+
+    ///this tick's pairs become the previous ones and `pairs` the current ones
+    ///they can be in any order, reversed, or given twice, the buffer of the tick before is reused
+    pub fn update(&mut self, pairs: &[(usize, usize)]) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+        pairs.par_iter().map(|&(a, b)| pack(a, b)).collect_into_vec(&mut self.current);
+        self.sort_current();
+    }
+
+    ///`update` with the pairs of an already built tree, each task packs the pairs found below its branches in its own list
+    ///other structures give their pairs to `update`
+    pub fn update_from(&mut self, tree: &(impl Hierarchy + Sync + ?Sized)) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+        //every branch checks the collisions between its two children, like the traversals of the trees
+        let mut branches = Vec::new();
+        let mut stack: Vec<usize> = tree.root().into_iter().collect();
+        while let Some(node) = stack.pop() {
+            if let Some((left, right)) = tree.children(node) {
+                branches.push((left, right));
+                stack.extend([left, right]);
+            }
+        }
+        self.current = branches.into_par_iter().fold(Vec::new, |mut packed, (left, right)| {
+            let _ = recursive_cross_collision(tree, tree, left, right, &mut |a, b| {
+                packed.push(pack(a, b));
+                ControlFlow::<Infallible>::Continue(())
+            });
+            packed
+        }).reduce(Vec::new, |mut a, mut b| {
+            a.append(&mut b);
+            a
+        });
+        self.sort_current();
+    }
+
+    fn sort_current(&mut self) {
+        self.current.par_sort_unstable();
+        self.current.dedup();
+    }
+
+    ///this tick's overlapping pairs, sorted
+    pub fn pairs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.current.iter().map(|&pair| unpack(pair))
+    }
+
+    ///every event of the last update, sorted by pair, a simple merge of the two sorted lists
+    pub fn events(&self) -> Events<'_> {
+        Events {
+            previous: &self.previous,
+            current: &self.current,
+        }
+    }
+
+    ///pairs which started overlapping during the last update
+    pub fn began(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.events().filter_map(|event| match event {
+            ContactEvent::Begin(a, b) => Some((a, b)),
+            _ => None,
+        })
+    }
+
+    ///pairs which stopped overlapping during the last update
+    pub fn ended(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.events().filter_map(|event| match event {
+            ContactEvent::End(a, b) => Some((a, b)),
+            _ => None,
+        })
+    }
+
+    ///call `visitor` with every event, until it breaks, each rayon worker merges its own slice of both lists so the order is lost
+    ///it stops like `Broadphase::visit_collisions_par`
+    pub fn visit_events_par<B: Send>(&self, visitor: impl Fn(ContactEvent) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+        self.slices().into_par_iter().try_for_each(|events| {
+            for event in events {
+                visitor(event)?;
+            }
+            ControlFlow::Continue(())
+        })
+    }
+
+    pub fn counts(&self) -> EventCounts {
+        self.slices().into_par_iter().map(|events| {
+            let mut counts = EventCounts::default();
+            for event in events {
+                match event {
+                    ContactEvent::Begin(..) => counts.began += 1,
+                    ContactEvent::Persist(..) => counts.persisted += 1,
+                    ContactEvent::End(..) => counts.ended += 1,
+                }
+            }
+            counts
+        }).reduce(EventCounts::default, |a, b| EventCounts {
+            began: a.began + b.began,
+            persisted: a.persisted + b.persisted,
+            ended: a.ended + b.ended,
+        })
+    }
+
+    ///cut both lists at the same pairs, so each slice of the merge can be done on its own
+    fn slices(&self) -> Vec<Events<'_>> {
+        let longest = if self.current.len() >= self.previous.len() { &self.current } else { &self.previous };
+        let count = (rayon::current_num_threads() * TASKS_PER_THREAD).min(longest.len()).max(1);
+        let mut slices = Vec::with_capacity(count);
+        let (mut previous_start, mut current_start) = (0, 0);
+        for i in 1..=count {
+            let (previous_end, current_end) = if i == count {
+                (self.previous.len(), self.current.len())
+            } else {
+                let cut = longest[i * longest.len() / count];
+                (self.previous.partition_point(|&pair| pair < cut), self.current.partition_point(|&pair| pair < cut))
+            };
+            slices.push(Events {
+                previous: &self.previous[previous_start..previous_end],
+                current: &self.current[current_start..current_end],
+            });
+            (previous_start, current_start) = (previous_end, current_end);
+        }
+        slices
+    }
+}
+
+///iterator returned by `PairCache::events`
+pub struct Events<'a> {
+    previous: &'a [u64],
+    current: &'a [u64],
+}
+
+impl Iterator for Events<'_> {
+    type Item = ContactEvent;
+
+    fn next(&mut self) -> Option<ContactEvent> {
+        let ordering = match (self.previous.first(), self.current.first()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(previous), Some(current)) => previous.cmp(current),
+        };
+        let event = match ordering {
+            Ordering::Less => {
+                let (a, b) = unpack(self.previous[0]);
+                self.previous = &self.previous[1..];
+                ContactEvent::End(a, b)
+            }
+            Ordering::Greater => {
+                let (a, b) = unpack(self.current[0]);
+                self.current = &self.current[1..];
+                ContactEvent::Begin(a, b)
+            }
+            Ordering::Equal => {
+                let (a, b) = unpack(self.current[0]);
+                self.previous = &self.previous[1..];
+                self.current = &self.current[1..];
+                ContactEvent::Persist(a, b)
+            }
+        };
+        Some(event)
+    }
+}
+
+///smallest index in the high half, so the packed pairs sort like the normalized tuples
+#[inline]
+fn pack(a: usize, b: usize) -> u64 {
+    let (low, high) = (a.min(b), a.max(b));
+    assert!(high <= u32::MAX as usize, "entity index {high} doesn't fit in 32 bits");
+    (low as u64) << 32 | high as u64
+}
+
+#[inline]
+fn unpack(pair: u64) -> (usize, usize) {
+    ((pair >> 32) as usize, (pair & u32::MAX as u64) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::brute_force::testing::{random_displacement, random_scene, LEAF_COUNTS, SEEDS};
+    use crate::brute_force::{normalize_pairs, BruteForce};
+    use crate::bvh5;
+    use crate::position::AABB;
+
+    #[test]
+    fn packed_pairs_sort_like_tuples() {
+        let pairs = [(0, 1), (0, u32::MAX as usize), (1, 0), (7, 3), (3, 8), (u32::MAX as usize - 1, u32::MAX as usize)];
+        let mut packed: Vec<u64> = pairs.iter().map(|&(a, b)| pack(a, b)).collect();
+        packed.sort_unstable();
+        let mut normalized: Vec<(usize, usize)> = pairs.iter().map(|&(a, b)| (a.min(b), a.max(b))).collect();
+        normalized.sort_unstable();
+        assert_eq!(packed.into_iter().map(unpack).collect::<Vec<_>>(), normalized);
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't fit in 32 bits")]
+    fn indices_past_32_bits_panic() {
+        PairCache::new().update(&[(0, u32::MAX as usize + 1)]);
+    }
+
+    #[test]
+    fn events_of_two_ticks() {
+        let mut cache = PairCache::new();
+        cache.update(&[(1, 0), (2, 3), (0, 1)]);
+        assert_eq!(cache.events().collect::<Vec<_>>(), vec![ContactEvent::Begin(0, 1), ContactEvent::Begin(2, 3)]);
+        cache.update(&[(3, 2), (4, 0)]);
+        assert_eq!(cache.events().collect::<Vec<_>>(), vec![ContactEvent::End(0, 1), ContactEvent::Begin(0, 4), ContactEvent::Persist(2, 3)]);
+        assert_eq!(cache.counts(), EventCounts { began: 1, persisted: 1, ended: 1 });
+        cache.update(&[]);
+        assert_eq!(cache.ended().collect::<Vec<_>>(), vec![(0, 4), (2, 3)]);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn events_match_oracle() {
+        let mut structure = bvh5::BVH::new();
+        let mut cache = PairCache::new();
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let count = LEAF_COUNTS[seed as usize % LEAF_COUNTS.len()];
+            let mut leaves = random_scene(&mut rng, count);
+            let mut previous: HashSet<(usize, usize)> = HashSet::new();
+            cache.clear();
+            for tick in 0..6 {
+                let mut oracle = BruteForce::new();
+                oracle.build(leaves.clone());
+                let current: HashSet<(usize, usize)> = normalize_pairs(oracle.get_collision_pairs()).into_iter().collect();
+                let mut expected: Vec<ContactEvent> = current.iter().map(|&(a, b)| if previous.contains(&(a, b)) { ContactEvent::Persist(a, b) } else { ContactEvent::Begin(a, b) })
+                    .chain(previous.difference(&current).map(|&(a, b)| ContactEvent::End(a, b)))
+                    .collect();
+                let pair = |event: &ContactEvent| match *event {
+                    ContactEvent::Begin(a, b) | ContactEvent::Persist(a, b) | ContactEvent::End(a, b) => (a, b),
+                };
+                expected.sort_unstable_by_key(pair);
+
+                structure.build(leaves.clone());
+                cache.update_from(&structure);
+                let context = format!("seed {seed}, {count} leaves, tick {tick}");
+                assert_eq!(cache.events().collect::<Vec<_>>(), expected, "wrong events with {context}");
+                let visited = Mutex::new(Vec::new());
+                let _ = cache.visit_events_par(|event| {
+                    visited.lock().unwrap().push(event);
+                    ControlFlow::<()>::Continue(())
+                });
+                let mut visited = visited.into_inner().unwrap();
+                visited.sort_unstable_by_key(pair);
+                assert_eq!(visited, expected, "wrong par events with {context}");
+                let counts = EventCounts {
+                    began: expected.iter().filter(|event| matches!(event, ContactEvent::Begin(..))).count(),
+                    persisted: expected.iter().filter(|event| matches!(event, ContactEvent::Persist(..))).count(),
+                    ended: expected.iter().filter(|event| matches!(event, ContactEvent::End(..))).count(),
+                };
+                assert_eq!(cache.counts(), counts, "wrong counts with {context}");
+
+                //some entities move, the others keep most of their pairs
+                for aabb in leaves.iter_mut() {
+                    if rng.gen_bool(0.2) {
+                        let displacement = random_displacement(&mut rng);
+                        *aabb = AABB::new(aabb.min() + displacement, aabb.max() + displacement);
+                    }
+                }
+                previous = current;
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::convert::Infallible;
+use std::ops::ControlFlow;
+use fixed::types::{I32F32, I64F64};
+use crate::hierarchy::Hierarchy;
+use crate::position::{EntityPos, AABB};
+
+///a convex volume is given as the intersection of its half spaces, a bitmask tracks which ones a subtree still straddles
+pub const MAX_HALF_SPACES: usize = 64;
+
+///every point `p` such that `normal · p <= offset`, the normal points out of the volume
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HalfSpace {
+    normal: EntityPos,
+    offset: I64F64, //dot products are done in I64F64, the products of two I32F32 don't fit in an I32F32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Containment {
+    Outside,
+    Straddling,
+    Inside,
+}
+
+impl HalfSpace {
+    ///bounded by the plane going through `point`, the normal doesn't need to be normalized
+    pub fn new(normal: EntityPos, point: &EntityPos) -> Self {
+        Self {
+            normal,
+            offset: dot(&normal, point),
+        }
+    }
+
+    ///the six half spaces of a box, so a box is also a convex volume
+    pub fn from_aabb(aabb: &AABB) -> [Self; 6] {
+        let axis = |axis: usize, sign: i32| {
+            let mut normal = EntityPos::zeros();
+            normal[axis] = I32F32::from_num(sign);
+            normal
+        };
+        [
+            Self::new(axis(0, -1), aabb.min()), Self::new(axis(0, 1), aabb.max()),
+            Self::new(axis(1, -1), aabb.min()), Self::new(axis(1, 1), aabb.max()),
+            Self::new(axis(2, -1), aabb.min()), Self::new(axis(2, 1), aabb.max()),
+        ]
+    }
+
+    ///only the two corners of the box going the furthest along and against the normal are tested
+    pub fn classify(&self, aabb: &AABB) -> Containment {
+        let mut lowest = EntityPos::zeros();
+        let mut highest = EntityPos::zeros();
+        for axis in 0..3 {
+            let (min, max) = (aabb.min()[axis], aabb.max()[axis]);
+            (lowest[axis], highest[axis]) = if self.normal[axis] >= I32F32::ZERO { (min, max) } else { (max, min) };
+        }
+        if dot(&self.normal, &lowest) > self.offset {
+            Containment::Outside
+        } else if dot(&self.normal, &highest) <= self.offset {
+            Containment::Inside
+        } else {
+            Containment::Straddling
+        }
+    }
+}
+
+fn dot(a: &EntityPos, b: &EntityPos) -> I64F64 {
+    a.iter().zip(b.iter()).fold(I64F64::ZERO, |total, (a, b)| {
+        total.saturating_add(I64F64::from_num(*a).saturating_mul(I64F64::from_num(*b)))
+    })
+}
+
+///how the box sits compared to the volume made of every half space
+pub fn classify(half_spaces: &[HalfSpace], aabb: &AABB) -> Containment {
+    let mut result = Containment::Inside;
+    for half_space in half_spaces {
+        match half_space.classify(aabb) {
+            Containment::Outside => return Containment::Outside,
+            Containment::Straddling => result = Containment::Straddling,
+            Containment::Inside => {}
+        }
+    }
+    result
+}
+
+///every entity whose box is inside or straddling the volume, in tree order
+///the planes are tested one by one, so a box close to an edge of the volume can be kept even if it's outside
+pub fn cull(tree: &(impl Hierarchy + ?Sized), half_spaces: &[HalfSpace]) -> Vec<usize> {
+    let mut entities = Vec::new();
+    let _ = visit_culled(tree, half_spaces, |entity| {
+        entities.push(entity);
+        ControlFlow::<Infallible>::Continue(())
+    });
+    entities
+}
+
+///same as `cull`, calling `visitor` with each entity until it breaks
+///once a node is fully inside the volume, its whole subtree is accepted without testing anything else
+pub fn visit_culled<B>(tree: &(impl Hierarchy + ?Sized), half_spaces: &[HalfSpace], mut visitor: impl FnMut(usize) -> ControlFlow<B>) -> ControlFlow<B> {
+    assert!(half_spaces.len() <= MAX_HALF_SPACES, "at most {MAX_HALF_SPACES} half spaces");
+    let all = if half_spaces.len() == MAX_HALF_SPACES { u64::MAX } else { (1 << half_spaces.len()) - 1 };
+    let mut stack: Vec<(usize, u64)> = tree.root().map(|root| (root, all)).into_iter().collect();
+    while let Some((node, mut straddled)) = stack.pop() {
+        let aabb = tree.node_aabb(node);
+        let mut outside = false;
+        let mut remaining = straddled;
+        while remaining != 0 { //only the half spaces the parent straddles can cut this node
+            let i = remaining.trailing_zeros() as usize;
+            remaining &= remaining - 1;
+            match half_spaces[i].classify(aabb) {
+                Containment::Outside => {
+                    outside = true;
+                    break;
+                }
+                Containment::Inside => straddled &= !(1 << i),
+                Containment::Straddling => {}
+            }
+        }
+        if outside {
+            continue;
+        }
+        if straddled == 0 {
+            visit_subtree(tree, node, &mut visitor)?;
+            continue;
+        }
+        match tree.children(node) {
+            None => visitor(tree.entity(node))?,
+            Some((left, right)) => {
+                stack.push((right, straddled));
+                stack.push((left, straddled));
+            }
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+fn visit_subtree<B>(tree: &(impl Hierarchy + ?Sized), node: usize, visitor: &mut impl FnMut(usize) -> ControlFlow<B>) -> ControlFlow<B> {
+    match tree.children(node) {
+        None => visitor(tree.entity(node)),
+        Some((left, right)) => {
+            visit_subtree(tree, left, visitor)?;
+            visit_subtree(tree, right, visitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::brute_force::testing::{random_coord, random_scene, LEAF_COUNTS, SEEDS};
+    use crate::position::new_fixed_vec;
+    use crate::{bvh4, bvh5};
+
+    ///a box or a random convex volume around the origin, made of planes with small integer normals
+    fn random_volume(rng: &mut StdRng) -> Vec<HalfSpace> {
+        if rng.gen_bool(0.3) {
+            let region = random_scene(rng, 1)[0];
+            return HalfSpace::from_aabb(&region).to_vec();
+        }
+        (0..rng.gen_range(1..8)).map(|_| {
+            let normal: EntityPos = new_fixed_vec(rng.gen_range(-3..=3), rng.gen_range(-3..=3), rng.gen_range(-3..=3));
+            let point: EntityPos = new_fixed_vec(random_coord(rng, 40, false), random_coord(rng, 40, false), random_coord(rng, 40, false));
+            HalfSpace::new(normal, &point)
+        }).collect()
+    }
+
+    #[test]
+    fn matches_oracle() {
+        let mut bvh4 = bvh4::BVH::new();
+        let mut bvh5 = bvh5::BVH::new();
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for count in LEAF_COUNTS {
+                let leaves = random_scene(&mut rng, count);
+                bvh4.build(leaves.clone());
+                bvh5.build(leaves.clone());
+                for _ in 0..8 {
+                    let volume = random_volume(&mut rng);
+                    let expected: Vec<usize> = (0..count).filter(|&i| classify(&volume, &leaves[i]) != Containment::Outside).collect();
+                    for (name, mut found) in [("bvh4", cull(&bvh4, &volume)), ("bvh5", cull(&bvh5, &volume))] {
+                        found.sort_unstable();
+                        assert_eq!(found, expected, "{name}: wrong entities with seed {seed} and {count} leaves");
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::cmp::Ordering;
+use crate::broadphase::Broadphase;
+use crate::brute_force::normalize_pairs;
+use crate::position::AABB;
+
+///how many pairs of each kind are explained, finding the path of a leaf is O(n)
+const MAX_EXPLAINED: usize = 20;
+
+///pairs reported by only one of two structures, a pair reported twice counts as an extra pair
+pub struct PairDiff {
+    pub missing: Vec<(usize, usize)>, //reported by the reference, but not by the candidate
+    pub extra: Vec<(usize, usize)>, //reported by the candidate, but not by the reference
+}
+
+impl PairDiff {
+    ///both structures must be built from the same leaves
+    pub fn new(reference: &dyn Broadphase, candidate: &dyn Broadphase) -> Self {
+        let expected = normalize_pairs(reference.get_collision_pairs());
+        let found = normalize_pairs(candidate.get_collision_pairs());
+
+        let mut missing = Vec::new();
+        let mut extra = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < expected.len() && j < found.len() { //both are sorted, so a simple merge is enough
+            match expected[i].cmp(&found[j]) {
+                Ordering::Less => {
+                    missing.push(expected[i]);
+                    i += 1;
+                }
+                Ordering::Greater => {
+                    extra.push(found[j]);
+                    j += 1;
+                }
+                Ordering::Equal => {
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        missing.extend_from_slice(&expected[i..]);
+        extra.extend_from_slice(&found[j..]);
+
+        Self { missing, extra }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty()
+    }
+
+    ///print the missing and extra pairs, with the boxes of both entities and where they are in each structure
+    pub fn print(&self, reference: &dyn Broadphase, candidate: &dyn Broadphase, leaves: &[AABB]) {
+        println!("{} missing and {} extra pairs in {} compared to {}", self.missing.len(), self.extra.len(), candidate.name(), reference.name());
+        for (kind, pairs) in [("missing", &self.missing), ("extra", &self.extra)] {
+            for &(a, b) in pairs.iter().take(MAX_EXPLAINED) {
+                println!("{kind} pair ({a}, {b}), intersects: {}", leaves[a].intersects(&leaves[b]));
+                for entity in [a, b] {
+                    println!("    {entity}: {:?} -> {:?}", leaves[entity].min(), leaves[entity].max());
+                    for structure in [reference, candidate] {
+                        println!("        in {}: {}", structure.name(), format_path(structure.leaf_path(entity)));
+                    }
+                }
+            }
+            if pairs.len() > MAX_EXPLAINED {
+                println!("... and {} more {kind} pairs", pairs.len() - MAX_EXPLAINED);
+            }
+        }
+    }
+}
+
+fn format_path(path: Option<Vec<usize>>) -> String {
+    match path {
+        None => "no tree path".to_string(),
+        Some(path) => path.iter().map(|node| node.to_string()).collect::<Vec<_>>().join(" -> "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brute_force::BruteForce;
+    use crate::position::{new_fixed_vec, EntityPos};
+    use crate::{bvh2, bvh3, bvh4, bvh5};
+
+    fn leaves() -> Vec<AABB> {
+        (0..9).map(|i| {
+            let center: EntityPos = new_fixed_vec(i * 3, i % 2, 0);
+            AABB::from_center(center, new_fixed_vec(2, 2, 2))
+        }).collect()
+    }
+
+    #[test]
+    fn same_pairs_make_an_empty_diff() {
+        let mut reference = BruteForce::new();
+        reference.build(leaves());
+        let mut candidate = bvh5::BVH::new();
+        candidate.build(leaves());
+        assert!(PairDiff::new(&reference, &candidate).is_empty());
+    }
+
+    #[test]
+    fn missing_and_extra_pairs() {
+        let mut reference = BruteForce::new();
+        reference.build(leaves());
+        let mut candidate = BruteForce::new();
+        let mut moved = leaves();
+        moved[0] = AABB::from_center(new_fixed_vec(100, 0, 0), new_fixed_vec(1, 1, 1));
+        moved[8] = AABB::from_center(new_fixed_vec(100, 0, 0), new_fixed_vec(1, 1, 1));
+        candidate.build(moved);
+
+        let diff = PairDiff::new(&reference, &candidate);
+        assert_eq!(diff.missing, vec![(0, 1), (7, 8)]);
+        assert_eq!(diff.extra, vec![(0, 8)]);
+    }
+
+    #[test]
+    fn leaf_paths_end_at_the_entity() {
+        let structures: Vec<Box<dyn Broadphase>> = vec![
+            Box::new(bvh2::BVH::new()),
+            Box::new(bvh3::BVH::new()),
+            Box::new(bvh4::BVH::new()),
+            Box::new(bvh5::BVH::new()),
+        ];
+        for mut structure in structures {
+            structure.build(leaves());
+            let mut ends = Vec::new();
+            for entity in 0..leaves().len() {
+                let path = structure.leaf_path(entity).unwrap();
+                assert!(path.len() > 1, "{}", structure.name());
+                ends.push(*path.last().unwrap());
+            }
+            ends.sort_unstable();
+            ends.dedup();
+            assert_eq!(ends.len(), leaves().len(), "{}: two entities share a leaf", structure.name());
+            assert!(structure.leaf_path(leaves().len()).is_none());
+        }
+    }
+}
@@ -0,0 +1,553 @@
+use std::ops::ControlFlow;
+use fixed::types::{I32F32, I64F64};
+use rayon::prelude::*;
+use crate::bipartite::recursive_cross_collision;
+use crate::broadphase::{collector, counter, Broadphase};
+use crate::bvh4::{Node, NodeKind};
+use crate::hierarchy::{query_aabb, Hierarchy};
+use crate::position::{EntityPos, AABB};
+
+const NULL: usize = usize::MAX;
+
+///name of an entity in a `DynamicTree`, it stays the same when the tree is rebalanced, and is reused once removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Handle(usize);
+
+impl Handle {
+    ///the entity index reported by the collision queries
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+///AABB tree updated one entity at a time, like Box2D's dynamic tree
+///nodes use the `bvh4` layout, but their order means nothing, freed nodes are reused by the next insertions
+///with a margin, the leaves hold a fat box around the entity, and moves staying inside it don't touch the tree
+#[derive(Default)]
+pub struct DynamicTree {
+    nodes: Vec<Node>, //the leaves hold the index of their handle and its fat box
+    parents: Vec<usize>, //NULL for the root and the free nodes
+    heights: Vec<u32>, //0 for leaves, the rotations keep the tree close to balanced
+    free_nodes: Vec<usize>,
+    leaves: Vec<usize>, //leaf node of each handle, NULL once removed
+    free_handles: Vec<usize>,
+    tight: Vec<AABB>, //exact box of each handle, what the queries report
+    margin: I32F32,
+    root: Option<usize>,
+}
+
+impl DynamicTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///the fat boxes are grown by `margin` on every side, bigger margins mean fewer updates but more nodes to check
+    pub fn with_margin(margin: I32F32) -> Self {
+        Self {
+            margin,
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len() - self.free_handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.parents.clear();
+        self.heights.clear();
+        self.free_nodes.clear();
+        self.leaves.clear();
+        self.free_handles.clear();
+        self.tight.clear();
+        self.root = None;
+    }
+
+    ///the exact box of the entity, as given to the last insert or update
+    pub fn aabb(&self, handle: Handle) -> &AABB {
+        self.leaf(handle);
+        &self.tight[handle.0]
+    }
+
+    ///the box stored in the tree, holding `aabb` and the margin around it
+    pub fn fat_aabb(&self, handle: Handle) -> &AABB {
+        &self.nodes[self.leaf(handle)].aabb
+    }
+
+    ///height of the root, 0 for a single leaf
+    pub fn height(&self) -> u32 {
+        self.root.map_or(0, |root| self.heights[root])
+    }
+
+    pub fn insert(&mut self, aabb: AABB) -> Handle {
+        let handle = self.free_handles.pop().unwrap_or_else(|| {
+            self.leaves.push(NULL);
+            self.tight.push(AABB::empty());
+            self.leaves.len() - 1
+        });
+        self.tight[handle] = aabb;
+        let fat = aabb.fattened(self.margin, &EntityPos::zeros());
+        let leaf = self.allocate(Node { aabb: fat, kind: NodeKind::Leaf(handle) });
+        self.leaves[handle] = leaf;
+        self.insert_leaf(leaf);
+        Handle(handle)
+    }
+
+    pub fn remove(&mut self, handle: Handle) {
+        let leaf = self.leaf(handle);
+        self.remove_leaf(leaf);
+        self.free_nodes.push(leaf);
+        self.leaves[handle.0] = NULL;
+        self.free_handles.push(handle.0);
+    }
+
+    ///move the entity, returns whether the tree changed
+    ///nothing but the exact box is written while it stays in the fat box, else the leaf is inserted again with a new fat box
+    pub fn update(&mut self, handle: Handle, aabb: AABB) -> bool {
+        self.update_moving(handle, aabb, &EntityPos::zeros())
+    }
+
+    ///same as `update`, a new fat box is also stretched by `displacement`, the expected move of the next tick
+    ///so an entity moving steadily keeps its leaf for a few ticks, the way a margin alone would only do for a slow one
+    pub fn update_moving(&mut self, handle: Handle, aabb: AABB, displacement: &EntityPos) -> bool {
+        let leaf = self.leaf(handle);
+        self.tight[handle.0] = aabb;
+        if self.nodes[leaf].aabb.contains(&aabb) {
+            return false;
+        }
+        self.remove_leaf(leaf);
+        self.nodes[leaf].aabb = aabb.fattened(self.margin, displacement);
+        self.insert_leaf(leaf);
+        true
+    }
+
+    fn leaf(&self, handle: Handle) -> usize {
+        let leaf = self.leaves[handle.0];
+        assert_ne!(leaf, NULL, "{handle:?} was removed");
+        leaf
+    }
+
+    fn allocate(&mut self, node: Node) -> usize {
+        match self.free_nodes.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                self.parents[index] = NULL;
+                self.heights[index] = 0;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.parents.push(NULL);
+                self.heights.push(0);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf: usize) {
+        let Some(root) = self.root else {
+            self.root = Some(leaf);
+            self.parents[leaf] = NULL;
+            return;
+        };
+        let aabb = self.nodes[leaf].aabb;
+        let sibling = self.find_sibling(root, &aabb);
+
+        //the sibling and the new leaf share a new branch, put where the sibling was
+        let old_parent = self.parents[sibling];
+        let parent = self.allocate(Node { aabb: aabb.union(&self.nodes[sibling].aabb), kind: NodeKind::Branch(sibling, leaf) });
+        self.parents[parent] = old_parent;
+        self.heights[parent] = self.heights[sibling] + 1;
+        if old_parent == NULL {
+            self.root = Some(parent);
+        } else {
+            self.replace_child(old_parent, sibling, parent);
+        }
+        self.parents[sibling] = parent;
+        self.parents[leaf] = parent;
+        self.fix_upward(parent);
+    }
+
+    fn remove_leaf(&mut self, leaf: usize) {
+        if self.root == Some(leaf) {
+            self.root = None;
+            return;
+        }
+        //the sibling takes the place of the parent, which isn't needed anymore
+        let parent = self.parents[leaf];
+        let grand_parent = self.parents[parent];
+        let NodeKind::Branch(left, right) = self.nodes[parent].kind else { unreachable!("the parent of a node is a branch") };
+        let sibling = if left == leaf { right } else { left };
+        self.parents[sibling] = grand_parent;
+        if grand_parent == NULL {
+            self.root = Some(sibling);
+        } else {
+            self.replace_child(grand_parent, parent, sibling);
+            self.fix_upward(grand_parent);
+        }
+        self.parents[leaf] = NULL;
+        self.free_nodes.push(parent);
+    }
+
+    ///descend toward the child whose cost grows the least, and stop when pairing with the current node is cheaper
+    ///the cost is the surface area added to the tree, including what every ancestor grows to hold the new box
+    fn find_sibling(&self, mut index: usize, aabb: &AABB) -> usize {
+        let two = I64F64::from_num(2);
+        while let NodeKind::Branch(left, right) = self.nodes[index].kind {
+            let area = self.nodes[index].aabb.surface_area();
+            let combined = self.nodes[index].aabb.union(aabb).surface_area();
+            let cost = combined.saturating_mul(two); //a new branch above this node
+            let inheritance = combined.saturating_sub(area).saturating_mul(two); //paid by this node if we go further down
+            let child_cost = |child: usize| {
+                let enlarged = self.nodes[child].aabb.union(aabb).surface_area();
+                let added = match self.nodes[child].kind {
+                    NodeKind::Leaf(_) => enlarged,
+                    NodeKind::Branch(..) => enlarged.saturating_sub(self.nodes[child].aabb.surface_area()),
+                };
+                added.saturating_add(inheritance)
+            };
+            let (cost_left, cost_right) = (child_cost(left), child_cost(right));
+            if cost < cost_left && cost < cost_right {
+                break;
+            }
+            index = if cost_left <= cost_right { left } else { right };
+        }
+        index
+    }
+
+    fn replace_child(&mut self, parent: usize, old: usize, new: usize) {
+        let NodeKind::Branch(left, right) = self.nodes[parent].kind else { unreachable!("the parent of a node is a branch") };
+        self.nodes[parent].kind = if left == old { NodeKind::Branch(new, right) } else { NodeKind::Branch(left, new) };
+    }
+
+    ///recompute the box and the height of every node from `index` up to the root, rotating the unbalanced ones
+    fn fix_upward(&mut self, mut index: usize) {
+        while index != NULL {
+            index = self.balance(index);
+            self.refit_node(index);
+            index = self.parents[index];
+        }
+    }
+
+    fn refit_node(&mut self, index: usize) {
+        let NodeKind::Branch(left, right) = self.nodes[index].kind else { return; };
+        self.nodes[index].aabb = self.nodes[left].aabb.union(&self.nodes[right].aabb);
+        self.heights[index] = 1 + self.heights[left].max(self.heights[right]);
+    }
+
+    ///AVL rotation when a child is taller than the other by more than 1, returns the node now standing where `a` was
+    fn balance(&mut self, a: usize) -> usize {
+        let NodeKind::Branch(b, c) = self.nodes[a].kind else { return a; };
+        if self.heights[b] + 1 < self.heights[c] {
+            self.rotate(a, c, b);
+            c
+        } else if self.heights[c] + 1 < self.heights[b] {
+            self.rotate(a, b, c);
+            b
+        } else {
+            a
+        }
+    }
+
+    ///`up` takes the place of its parent `a`, `a` keeps `other` and gets the shortest child of `up`
+    fn rotate(&mut self, a: usize, up: usize, other: usize) {
+        let NodeKind::Branch(f, g) = self.nodes[up].kind else { unreachable!("the tallest child is a branch") };
+        let parent = self.parents[a];
+        self.parents[up] = parent;
+        self.parents[a] = up;
+        if parent == NULL {
+            self.root = Some(up);
+        } else {
+            self.replace_child(parent, a, up);
+        }
+
+        let (kept, moved) = if self.heights[f] > self.heights[g] { (f, g) } else { (g, f) };
+        self.nodes[a].kind = NodeKind::Branch(other, moved);
+        self.parents[moved] = a;
+        self.nodes[up].kind = NodeKind::Branch(a, kept);
+        self.refit_node(a);
+        self.refit_node(up);
+    }
+
+    ///every live branch, found from the root since the free nodes are mixed with the others
+    fn branches(&self) -> Vec<usize> {
+        let mut branches = Vec::new();
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            if let NodeKind::Branch(left, right) = self.nodes[node].kind {
+                branches.push(node);
+                stack.push(left);
+                stack.push(right);
+            }
+        }
+        branches
+    }
+
+    pub fn get_collision(&self) -> usize {
+        let mut output = 0;
+        let _ = self.visit_collisions(counter(&mut output));
+        output
+    }
+
+    pub fn get_collision_par(&self) -> usize {
+        self.branches().into_par_iter().map(|node| {
+            let mut output = 0;
+            let NodeKind::Branch(left, right) = self.nodes[node].kind else { unreachable!() };
+            let _ = recursive_cross_collision(self, self, left, right, &mut counter(&mut output));
+            output
+        }).sum()
+    }
+
+    ///every intersecting pair, as handle indices
+    pub fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(collector(&mut pairs));
+        pairs
+    }
+
+    pub fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        self.branches().into_par_iter().fold(Vec::new, |mut pairs, node| {
+            let NodeKind::Branch(left, right) = self.nodes[node].kind else { unreachable!() };
+            let _ = recursive_cross_collision(self, self, left, right, &mut collector(&mut pairs));
+            pairs
+        }).reduce(Vec::new, |mut a, mut b| {
+            a.append(&mut b);
+            a
+        })
+    }
+
+    ///call `visitor` with the handle indices of every intersecting pair, until it breaks
+    ///each branch checks the collisions between its two children, like `bvh4`
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        for node in self.branches() {
+            let NodeKind::Branch(left, right) = self.nodes[node].kind else { unreachable!() };
+            recursive_cross_collision(self, self, left, right, &mut visitor)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    ///same as `visit_collisions`, stopping like `Broadphase::visit_collisions_par`
+    pub fn visit_collisions_par<B: Send>(&self, visitor: impl Fn(usize, usize) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+        self.branches().into_par_iter().try_for_each(|node| {
+            let NodeKind::Branch(left, right) = self.nodes[node].kind else { unreachable!() };
+            recursive_cross_collision(self, self, left, right, &mut |a, b| visitor(a, b))
+        })
+    }
+
+    ///nodes from the root down to the leaf of this handle, cheap here since every node knows its parent
+    pub fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        let mut current = *self.leaves.get(entity).filter(|leaf| **leaf != NULL)?;
+        let mut path = vec![current];
+        while self.parents[current] != NULL {
+            current = self.parents[current];
+            path.push(current);
+        }
+        path.reverse();
+        Some(path)
+    }
+}
+
+impl Hierarchy for DynamicTree {
+    fn root(&self) -> Option<usize> {
+        self.root
+    }
+
+    ///the exact box for a leaf, so the pairs and queries don't see the margin
+    fn node_aabb(&self, node: usize) -> &AABB {
+        match self.nodes[node].kind {
+            NodeKind::Leaf(handle) => &self.tight[handle],
+            NodeKind::Branch(..) => &self.nodes[node].aabb,
+        }
+    }
+
+    fn children(&self, node: usize) -> Option<(usize, usize)> {
+        match self.nodes[node].kind {
+            NodeKind::Branch(left, right) => Some((left, right)),
+            NodeKind::Leaf(_) => None,
+        }
+    }
+
+    fn entity(&self, leaf: usize) -> usize {
+        match self.nodes[leaf].kind {
+            NodeKind::Leaf(handle) => handle,
+            NodeKind::Branch(..) => panic!("node {leaf} is a branch"),
+        }
+    }
+}
+
+impl Broadphase for DynamicTree {
+    fn name(&self) -> &'static str {
+        "dynamic"
+    }
+
+    ///insert the leaves one by one in an empty tree, so the handles are the indices in `leaves`
+    fn build(&mut self, leaves: Vec<AABB>) {
+        self.clear();
+        for aabb in leaves {
+            self.insert(aabb);
+        }
+    }
+
+    fn get_collision(&self) -> usize {
+        DynamicTree::get_collision(self)
+    }
+
+    fn get_collision_par(&self) -> usize {
+        DynamicTree::get_collision_par(self)
+    }
+
+    fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        DynamicTree::get_collision_pairs(self)
+    }
+
+    fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        DynamicTree::get_collision_pairs_par(self)
+    }
+
+    fn visit_collisions(&self, visitor: &mut dyn FnMut(usize, usize) -> ControlFlow<()>) -> ControlFlow<()> {
+        DynamicTree::visit_collisions(self, visitor)
+    }
+
+    fn visit_collisions_par(&self, visitor: &(dyn Fn(usize, usize) -> ControlFlow<()> + Sync)) -> ControlFlow<()> {
+        DynamicTree::visit_collisions_par(self, visitor)
+    }
+
+    fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        query_aabb(self, region)
+    }
+
+    fn leaf_path(&self, entity: usize) -> Option<Vec<usize>> {
+        DynamicTree::leaf_path(self, entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::brute_force::{normalize_pairs, BruteForce};
+    use crate::position::{new_fixed_vec, EntityPos};
+
+    ///parents, heights and boxes of every node reachable from the root
+    fn validate(tree: &DynamicTree) {
+        let Some(root) = tree.root else {
+            assert!(tree.is_empty());
+            return;
+        };
+        assert_eq!(tree.parents[root], NULL);
+        let mut leaf_count = 0;
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            match tree.nodes[node].kind {
+                NodeKind::Leaf(handle) => {
+                    assert_eq!(tree.leaves[handle], node);
+                    assert_eq!(tree.heights[node], 0);
+                    assert!(tree.nodes[node].aabb.contains(&tree.tight[handle]));
+                    leaf_count += 1;
+                }
+                NodeKind::Branch(left, right) => {
+                    assert_eq!((tree.parents[left], tree.parents[right]), (node, node));
+                    assert_eq!(tree.heights[node], 1 + tree.heights[left].max(tree.heights[right]));
+                    assert_eq!(tree.nodes[node].aabb, tree.nodes[left].aabb.union(&tree.nodes[right].aabb));
+                    stack.extend([left, right]);
+                }
+            }
+        }
+        assert_eq!(leaf_count, tree.len());
+    }
+
+    fn random_box(rng: &mut StdRng) -> AABB {
+        let center: EntityPos = new_fixed_vec(rng.gen_range(-50..50), rng.gen_range(-50..50), rng.gen_range(-50..50));
+        AABB::from_center(center, new_fixed_vec(rng.gen_range(0..5), rng.gen_range(0..5), rng.gen_range(0..5)))
+    }
+
+    #[test]
+    fn random_insertions_removals_and_moves() {
+        for seed in 0..8 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut tree = DynamicTree::with_margin(I32F32::from_num(seed % 3));
+            let mut alive: Vec<(Handle, AABB)> = Vec::new();
+            for step in 0..2000 {
+                match rng.gen_range(0..10) {
+                    0..=4 => {
+                        let aabb = random_box(&mut rng);
+                        alive.push((tree.insert(aabb), aabb));
+                    }
+                    5..=6 if !alive.is_empty() => {
+                        let (handle, _) = alive.swap_remove(rng.gen_range(0..alive.len()));
+                        tree.remove(handle);
+                    }
+                    7 if !alive.is_empty() => {
+                        let i = rng.gen_range(0..alive.len());
+                        alive[i].1 = random_box(&mut rng);
+                        tree.update(alive[i].0, alive[i].1);
+                    }
+                    _ if !alive.is_empty() => {
+                        //small moves, most of them stay in their fat box
+                        let i = rng.gen_range(0..alive.len());
+                        let displacement: EntityPos = new_fixed_vec(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                        alive[i].1 = AABB::new(alive[i].1.min() + displacement, alive[i].1.max() + displacement);
+                        tree.update_moving(alive[i].0, alive[i].1, &displacement);
+                    }
+                    _ => {}
+                }
+                if step % 100 == 0 {
+                    validate(&tree);
+                    //the oracle numbers the boxes by position in `alive`, the tree by handle
+                    let mut oracle = BruteForce::new();
+                    oracle.build(alive.iter().map(|(_, aabb)| *aabb).collect());
+                    let expected = normalize_pairs(oracle.get_collision_pairs().into_iter().map(|(a, b)| (alive[a].0.index(), alive[b].0.index())).collect());
+                    assert_eq!(normalize_pairs(tree.get_collision_pairs()), expected, "seed {seed}, step {step}");
+                }
+            }
+            validate(&tree);
+            for (handle, aabb) in alive.iter() {
+                assert_eq!(tree.aabb(*handle), aabb);
+            }
+            assert!(tree.height() <= 2 * (tree.len() as f64).log2().ceil() as u32 + 1, "height {} for {} leaves", tree.height(), tree.len());
+        }
+    }
+
+    #[test]
+    fn moves_inside_the_margin_keep_the_tree() {
+        let mut tree = DynamicTree::with_margin(I32F32::ONE);
+        let a = tree.insert(AABB::from_center(new_fixed_vec(0, 0, 0), new_fixed_vec(1, 1, 1)));
+        let b = tree.insert(AABB::from_center(new_fixed_vec(3, 0, 0), new_fixed_vec(1, 1, 1)));
+        assert!(tree.fat_aabb(a).intersects(tree.fat_aabb(b)));
+        assert!(tree.get_collision_pairs().is_empty(), "only the fat boxes overlap");
+
+        assert!(!tree.update(a, AABB::from_center(new_fixed_vec(0.75, 0, 0), new_fixed_vec(1, 1, 1))));
+        assert_eq!(tree.get_collision_pairs().len(), 0);
+        assert!(!tree.update(a, AABB::from_center(new_fixed_vec(1, 0, 0), new_fixed_vec(1, 1, 1))));
+        assert_eq!(tree.get_collision_pairs().len(), 1, "touching with the exact boxes");
+        assert!(tree.update(a, AABB::from_center(new_fixed_vec(1.5, 0, 0), new_fixed_vec(1, 1, 1))));
+
+        //stretched toward the movement, the next moves in the same direction fit
+        let displacement = new_fixed_vec(-4, 0, 0);
+        assert!(tree.update_moving(b, AABB::from_center(new_fixed_vec(10, 0, 0), new_fixed_vec(1, 1, 1)), &displacement));
+        assert!(!tree.update_moving(b, AABB::from_center(new_fixed_vec(6, 0, 0), new_fixed_vec(1, 1, 1)), &displacement));
+        assert!(tree.get_collision_pairs().is_empty());
+        validate(&tree);
+    }
+
+    #[test]
+    fn handles_are_reused() {
+        let mut tree = DynamicTree::new();
+        let a = tree.insert(AABB::empty());
+        let b = tree.insert(AABB::empty());
+        tree.remove(a);
+        assert_eq!(tree.insert(AABB::empty()), a);
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.get_collision_pairs().len(), 1);
+        tree.remove(a);
+        tree.remove(b);
+        assert!(tree.is_empty());
+        validate(&tree);
+    }
+}
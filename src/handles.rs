@@ -0,0 +1,315 @@
+use std::ops::ControlFlow;
+use crate::broadphase::Broadphase;
+use crate::bvh5;
+use crate::hierarchy::{query_aabb, Hierarchy};
+use crate::position::AABB;
+
+const NULL: usize = usize::MAX;
+
+///name of an entity in `Entities`, valid until it's removed, even though its leaf moves at every build
+///the generation tells apart the entities reusing the same index, so a handle kept after a remove is just invalid
+///an index is retired once its generation reaches u32::MAX instead of wrapping around, and past u32::MAX indices an insert panics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntityHandle {
+    index: u32,
+    generation: u32,
+}
+
+impl EntityHandle {
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+struct Record {
+    aabb: AABB,
+    generation: u32, //of the live entity, or of the next one once removed
+    alive: bool,
+    slot: usize, //leaf node in the structure, NULL until the next build
+}
+
+///entities addressed by handle on top of a structure whose leaves are reordered at every build, like `bvh4` and `bvh5`
+///inserts show up in the queries after the next build, moves after the next build or refit, removes right away
+pub struct Entities<S = bvh5::BVH> {
+    structure: S,
+    records: Vec<Record>, //indexed by handle index
+    free: Vec<u32>,
+    retired: usize, //records whose generation ran out, never reused
+    built: Vec<EntityHandle>, //handle of each entity given to the last build, what the structure reports
+    inserted: bool, //since the last build, so a refit has to build instead
+}
+
+impl<S: Broadphase + Hierarchy + Default> Default for Entities<S> {
+    fn default() -> Self {
+        Self::with_structure(S::default())
+    }
+}
+
+impl<S: Broadphase + Hierarchy + Default> Entities<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S: Broadphase + Hierarchy> Entities<S> {
+    pub fn with_structure(structure: S) -> Self {
+        Self {
+            structure,
+            records: Vec::new(),
+            free: Vec::new(),
+            retired: 0,
+            built: Vec::new(),
+            inserted: false,
+        }
+    }
+
+    pub fn structure(&self) -> &S {
+        &self.structure
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len() - self.free.len() - self.retired
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, handle: EntityHandle) -> bool {
+        self.records.get(handle.index()).is_some_and(|record| record.alive && record.generation == handle.generation)
+    }
+
+    pub fn insert(&mut self, aabb: AABB) -> EntityHandle {
+        self.inserted = true;
+        let index = self.free.pop().unwrap_or_else(|| {
+            self.records.push(Record { aabb, generation: 0, alive: false, slot: NULL });
+            handle_index(self.records.len() - 1)
+        });
+        let record = &mut self.records[index as usize];
+        record.aabb = aabb;
+        record.alive = true;
+        EntityHandle { index, generation: record.generation }
+    }
+
+    ///returns false if the handle was already invalid, its leaf stays in the structure until the next build but isn't reported anymore
+    pub fn remove(&mut self, handle: EntityHandle) -> bool {
+        let Some(record) = self.record_mut(handle) else { return false; };
+        record.alive = false;
+        record.slot = NULL;
+        match record.generation.checked_add(1) {
+            Some(generation) => {
+                record.generation = generation;
+                self.free.push(handle.index);
+            }
+            None => self.retired += 1, //wrapping would make the first handles of this index valid again
+        }
+        true
+    }
+
+    ///returns false if the handle is invalid
+    pub fn set_aabb(&mut self, handle: EntityHandle, aabb: AABB) -> bool {
+        let Some(record) = self.record_mut(handle) else { return false; };
+        record.aabb = aabb;
+        true
+    }
+
+    pub fn aabb(&self, handle: EntityHandle) -> Option<&AABB> {
+        self.record(handle).map(|record| &record.aabb)
+    }
+
+    ///node of the structure holding this entity, None if it was inserted after the last build
+    pub fn slot(&self, handle: EntityHandle) -> Option<usize> {
+        self.record(handle).map(|record| record.slot).filter(|slot| *slot != NULL)
+    }
+
+    ///build the structure from every live entity with `Broadphase::rebuild`, and find where each of them landed
+    pub fn build(&mut self) {
+        self.built.clear();
+        self.built.extend(self.records.iter().enumerate().filter(|(_, record)| record.alive).map(|(index, record)| EntityHandle { index: handle_index(index), generation: record.generation }));
+        let leaves = self.built.iter().map(|handle| self.records[handle.index()].aabb).collect();
+        self.structure.rebuild(leaves);
+        self.inserted = false;
+
+        let mut stack: Vec<usize> = self.structure.root().into_iter().collect();
+        while let Some(node) = stack.pop() {
+            match self.structure.children(node) {
+                None => self.records[self.built[self.structure.entity(node)].index()].slot = node,
+                Some((left, right)) => stack.extend([left, right]),
+            }
+        }
+    }
+
+    ///every intersecting pair of live entities, as of the last build or refit
+    pub fn get_collision_pairs(&self) -> Vec<(EntityHandle, EntityHandle)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(|a, b| {
+            pairs.push((a, b));
+            ControlFlow::<()>::Continue(())
+        });
+        pairs
+    }
+
+    ///call `visitor` with every intersecting pair of live entities, until it breaks
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(EntityHandle, EntityHandle) -> ControlFlow<B>) -> ControlFlow<B> {
+        let mut result = ControlFlow::Continue(());
+        let _ = self.structure.visit_collisions(&mut |a, b| {
+            let (a, b) = (self.built[a], self.built[b]);
+            if !self.contains(a) || !self.contains(b) {
+                return ControlFlow::Continue(());
+            }
+            result = visitor(a, b);
+            if result.is_break() { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        result
+    }
+
+    ///every live entity whose box overlapped `region` at the last build or refit
+    pub fn query_aabb(&self, region: &AABB) -> Vec<EntityHandle> {
+        query_aabb(&self.structure, region).into_iter().map(|entity| self.built[entity]).filter(|handle| self.contains(*handle)).collect()
+    }
+
+    ///every other live entity overlapping this one, empty if the handle is invalid or it wasn't built yet
+    pub fn colliding_with(&self, handle: EntityHandle) -> Vec<EntityHandle> {
+        let Some(slot) = self.slot(handle) else { return Vec::new(); };
+        let mut found = self.query_aabb(self.structure.node_aabb(slot));
+        found.retain(|other| *other != handle);
+        found
+    }
+
+    fn record(&self, handle: EntityHandle) -> Option<&Record> {
+        self.contains(handle).then(|| &self.records[handle.index()])
+    }
+
+    fn record_mut(&mut self, handle: EntityHandle) -> Option<&mut Record> {
+        self.contains(handle).then(|| &mut self.records[handle.index()])
+    }
+}
+
+impl Entities<bvh5::BVH> {
+    ///move every entity to its box without changing the leaves, builds instead when entities were inserted since the last build
+    ///the removed entities keep their old box, they are only dropped at the next build
+    pub fn refit(&mut self) {
+        if self.inserted {
+            self.build();
+            return;
+        }
+        let leaves: Vec<AABB> = self.built.iter().map(|handle| self.records[handle.index()].aabb).collect();
+        self.structure.refit(&leaves);
+    }
+}
+
+#[inline]
+fn handle_index(index: usize) -> u32 {
+    assert!(index <= u32::MAX as usize, "entity index {index} doesn't fit in 32 bits");
+    index as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::brute_force::{normalize_pairs, BruteForce};
+    use crate::position::{new_fixed_vec, EntityPos};
+    use crate::{bvh4, dynamic};
+
+    fn random_box(rng: &mut StdRng) -> AABB {
+        let center: EntityPos = new_fixed_vec(rng.gen_range(-30..30), rng.gen_range(-30..30), rng.gen_range(-30..30));
+        AABB::from_center(center, new_fixed_vec(rng.gen_range(0..4), rng.gen_range(0..4), rng.gen_range(0..4)))
+    }
+
+    ///the pairs of the live entities, with the boxes they had at the last build or refit
+    fn check<S: Broadphase + Hierarchy>(entities: &Entities<S>, alive: &[(EntityHandle, AABB)], context: &str) {
+        let mut oracle = BruteForce::new();
+        oracle.build(alive.iter().map(|(_, aabb)| *aabb).collect());
+        let expected = normalize_pairs(oracle.get_collision_pairs().into_iter().map(|(a, b)| (alive[a].0.index(), alive[b].0.index())).collect());
+        let found = normalize_pairs(entities.get_collision_pairs().into_iter().map(|(a, b)| (a.index(), b.index())).collect());
+        assert_eq!(found, expected, "{context}");
+        for (handle, aabb) in alive {
+            let slot = entities.slot(*handle).unwrap();
+            assert_eq!(entities.built[entities.structure().entity(slot)], *handle, "{context}: wrong slot");
+            assert_eq!(entities.structure().node_aabb(slot), aabb, "{context}: wrong box in the slot");
+            let mut expected: Vec<EntityHandle> = alive.iter().filter(|(other, other_aabb)| other != handle && other_aabb.intersects(aabb)).map(|(other, _)| *other).collect();
+            expected.sort_unstable();
+            let mut found = entities.colliding_with(*handle);
+            found.sort_unstable();
+            assert_eq!(found, expected, "{context}: wrong entities colliding with {handle:?}");
+        }
+    }
+
+    fn random_operations<S: Broadphase + Hierarchy>(mut entities: Entities<S>, refit: impl Fn(&mut Entities<S>)) {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut alive: Vec<(EntityHandle, AABB)> = Vec::new();
+        let mut dead = Vec::new();
+        for tick in 0..30 {
+            for _ in 0..rng.gen_range(0..20) {
+                let aabb = random_box(&mut rng);
+                alive.push((entities.insert(aabb), aabb));
+            }
+            for _ in 0..rng.gen_range(0..8) {
+                if alive.is_empty() { break; }
+                let (handle, _) = alive.swap_remove(rng.gen_range(0..alive.len()));
+                assert!(entities.remove(handle));
+                dead.push(handle);
+            }
+            for (handle, aabb) in alive.iter_mut() {
+                if rng.gen_bool(0.3) {
+                    *aabb = random_box(&mut rng);
+                    assert!(entities.set_aabb(*handle, *aabb));
+                }
+            }
+            for handle in &dead {
+                assert!(!entities.contains(*handle) && !entities.remove(*handle) && entities.aabb(*handle).is_none(), "tick {tick}: {handle:?} was removed");
+            }
+            if tick % 2 == 0 { entities.build(); } else { refit(&mut entities); }
+            assert_eq!(entities.len(), alive.len());
+            check(&entities, &alive, &format!("tick {tick}"));
+        }
+    }
+
+    #[test]
+    fn handles_follow_their_leaves() {
+        random_operations(Entities::<bvh5::BVH>::new(), Entities::refit);
+        random_operations(Entities::<bvh4::BVH>::new(), Entities::build);
+        random_operations(Entities::<dynamic::DynamicTree>::new(), Entities::build);
+    }
+
+    #[test]
+    fn removed_handles_stay_invalid() {
+        let mut entities = Entities::<bvh5::BVH>::new();
+        let a = entities.insert(AABB::empty());
+        let b = entities.insert(AABB::empty());
+        entities.build();
+        assert_eq!(entities.get_collision_pairs().len(), 1);
+        assert!(entities.remove(a));
+        assert!(entities.get_collision_pairs().is_empty(), "removed entities aren't reported before the next build");
+
+        let c = entities.insert(AABB::empty());
+        assert_eq!(c.index(), a.index());
+        assert_ne!(c, a);
+        assert!(!entities.contains(a) && entities.contains(c));
+        assert_eq!(entities.slot(c), None, "not built yet");
+        entities.refit();
+        assert_eq!(normalize_pairs(entities.get_collision_pairs().into_iter().map(|(x, y)| (x.index(), y.index())).collect()), vec![(c.index(), b.index())]);
+    }
+
+    #[test]
+    fn exhausted_indices_are_retired() {
+        let mut entities = Entities::<bvh5::BVH>::new();
+        let a = entities.insert(AABB::empty());
+        entities.records[a.index()].generation = u32::MAX;
+        let a = EntityHandle { index: a.index, generation: u32::MAX };
+        assert!(entities.remove(a));
+        assert!(entities.is_empty());
+        let b = entities.insert(AABB::empty());
+        assert_ne!(b.index(), a.index(), "the index would give back generation 0");
+        assert_eq!(entities.len(), 1);
+        assert!(!entities.contains(a) && entities.contains(b));
+        entities.build();
+        assert_eq!(entities.query_aabb(&AABB::empty()), vec![b]);
+    }
+}
@@ -0,0 +1,34 @@
+use crate::position::AABB;
+
+///read only view of a tree of AABBs, so the spatial queries are written once for every BVH layout
+///nodes are identified by their index in the structure's own numbering, like in `Broadphase::leaf_path`
+pub trait Hierarchy {
+    ///None for an empty tree
+    fn root(&self) -> Option<usize>;
+
+    ///bounding box of the node, for a leaf it's the box of its entity
+    fn node_aabb(&self, node: usize) -> &AABB;
+
+    ///the two children of a branch, None for a leaf
+    fn children(&self, node: usize) -> Option<(usize, usize)>;
+
+    ///index in the vec given to build of the entity stored in this leaf, only meaningful for leaves
+    fn entity(&self, leaf: usize) -> usize;
+}
+
+///every entity whose box overlaps `region`, in tree order, for any tree unlike `bvh5::BVH::query_aabb`
+pub fn query_aabb(tree: &(impl Hierarchy + ?Sized), region: &AABB) -> Vec<usize> {
+    let mut entities = Vec::new();
+    let mut stack: Vec<usize> = tree.root().into_iter().collect();
+    while let Some(node) = stack.pop() {
+        if !tree.node_aabb(node).intersects(region) { continue; }
+        match tree.children(node) {
+            None => entities.push(tree.entity(node)),
+            Some((left, right)) => {
+                stack.push(right);
+                stack.push(left);
+            }
+        }
+    }
+    entities
+}
@@ -1,32 +1,34 @@
-use fixed::types::I32F32;
-use nalgebra::Vector3;
+use std::ops::ControlFlow;
 use rayon::prelude::*;
+use crate::broadphase::{collector, counter, Broadphase};
 use crate::morton::to_morton;
 use crate::position::{AABB, EntityPosExt};
 
 
-//This code must be wrong because of my morton implementation
+//every point of a box has a morton code between the codes of its min and max corners, because the morton code
+//grows with each coordinate, so two boxes can only intersect if their [min, max] morton intervals overlap
 struct Node {
     aabb: AABB,
+    index: usize, //index in the vec given to build, the list is sorted by morton code
     min: u128,
     max: u128,
 }
 
 impl Node {
-    fn new(aabb: AABB) -> Self {
+    fn new(index: usize, aabb: AABB) -> Self {
         let min = to_morton(aabb.min().block_pos());
-
-        let max = aabb.max() - Vector3::new(I32F32::DELTA, I32F32::DELTA, I32F32::DELTA);
-        let max = to_morton(max.block_pos());
+        let max = to_morton(aabb.max().block_pos()); //no need to shrink the box, touching boxes intersect too
 
         Self {
             aabb,
+            index,
             min,
             max,
         }
     }
 }
 
+#[derive(Default)]
 pub struct MortonList {
     nodes: Vec<Node>,
 }
@@ -39,22 +41,113 @@ impl MortonList {
     }
 
     pub fn build(&mut self, aabbs: Vec<AABB>) {
-        self.nodes = aabbs.into_par_iter().map(|aabb| Node::new(aabb)).collect();
+        self.nodes = aabbs.into_par_iter().enumerate().map(|(i, aabb)| Node::new(i, aabb)).collect();
         self.nodes.par_sort_unstable_by_key(|node| node.min);
     }
 
     pub fn get_collisions(&self) -> usize {
         self.nodes.par_iter().enumerate().map(|(i, node)| {
             let mut collisions = 0;
-            for other in self.nodes.iter().skip(i + 1) {
-                if node.max < other.min { //this can be really long with the wrong interval
-                    break;
-                }
-                if node.aabb.intersects(&other.aabb) {
-                    collisions += 1;
-                }
-            }
+            let _ = self.collisions_after(i, node, &mut counter(&mut collisions));
             collisions
         }).sum()
     }
+
+    pub fn get_collisions_seq(&self) -> usize {
+        let mut collisions = 0;
+        let _ = self.visit_collisions(counter(&mut collisions));
+        collisions
+    }
+
+    ///every intersecting pair, as indices in the vec given to `build`
+    pub fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(collector(&mut pairs));
+        pairs
+    }
+
+    pub fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        self.nodes.par_iter().enumerate().fold(Vec::new, |mut pairs, (i, node)| {
+            let _ = self.collisions_after(i, node, &mut collector(&mut pairs));
+            pairs
+        }).reduce(Vec::new, |mut a, mut b| {
+            a.append(&mut b);
+            a
+        })
+    }
+
+    ///call `visitor` with the indices of every intersecting pair, until it breaks
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        for (i, node) in self.nodes.iter().enumerate() {
+            self.collisions_after(i, node, &mut visitor)?;
+        }
+        ControlFlow::Continue(())
+    }
+
+    ///same as `visit_collisions`, stopping like `Broadphase::visit_collisions_par`
+    pub fn visit_collisions_par<B: Send>(&self, visitor: impl Fn(usize, usize) -> ControlFlow<B> + Sync) -> ControlFlow<B> {
+        self.nodes.par_iter().enumerate().try_for_each(|(i, node)| {
+            self.collisions_after(i, node, &mut |a, b| visitor(a, b))
+        })
+    }
+
+    ///every entity whose box overlaps `region`, only the ones whose interval starts before the end of the region's are checked
+    pub fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        let (min, max) = (to_morton(region.min().block_pos()), to_morton(region.max().block_pos()));
+        self.nodes.iter()
+            .take_while(|node| node.min <= max)
+            .filter(|node| node.max >= min && node.aabb.intersects(region))
+            .map(|node| node.index)
+            .collect()
+    }
+
+    fn collisions_after<B>(&self, i: usize, node: &Node, output: &mut impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        for other in self.nodes.iter().skip(i + 1) {
+            if node.max < other.min { //this can be really long with the wrong interval
+                break;
+            }
+            if node.aabb.intersects(&other.aabb) {
+                output(node.index, other.index)?;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+impl Broadphase for MortonList {
+    fn name(&self) -> &'static str {
+        "morton-based enclosing"
+    }
+
+    fn build(&mut self, leaves: Vec<AABB>) {
+        MortonList::build(self, leaves);
+    }
+
+    fn get_collision(&self) -> usize {
+        self.get_collisions_seq()
+    }
+
+    fn get_collision_par(&self) -> usize {
+        self.get_collisions()
+    }
+
+    fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        MortonList::get_collision_pairs(self)
+    }
+
+    fn get_collision_pairs_par(&self) -> Vec<(usize, usize)> {
+        MortonList::get_collision_pairs_par(self)
+    }
+
+    fn visit_collisions(&self, visitor: &mut dyn FnMut(usize, usize) -> ControlFlow<()>) -> ControlFlow<()> {
+        MortonList::visit_collisions(self, visitor)
+    }
+
+    fn visit_collisions_par(&self, visitor: &(dyn Fn(usize, usize) -> ControlFlow<()> + Sync)) -> ControlFlow<()> {
+        MortonList::visit_collisions_par(self, visitor)
+    }
+
+    fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        MortonList::query_aabb(self, region)
+    }
 }
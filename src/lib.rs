@@ -0,0 +1,26 @@
+pub mod batch;
+pub mod bipartite;
+pub mod broadphase;
+pub mod brute_force;
+pub mod bvh2;
+pub mod bvh3;
+pub mod bvh4;
+pub mod bvh5;
+pub mod coherence;
+pub mod contacts;
+pub mod culling;
+pub mod diff;
+pub mod dynamic;
+pub mod handles;
+pub mod hierarchy;
+pub mod homemade;
+pub mod morton;
+pub mod nearest;
+pub mod position;
+pub mod ray;
+pub mod sah;
+pub mod scene;
+pub mod static_grid;
+pub mod sweep;
+pub mod world;
+//mod bvh6;
@@ -1,221 +1,226 @@
-#![feature(iter_array_chunks)]
-
-use crate::position::{new_fixed_vec, EntityPos, EntityPosExt, AABB};
-use rand::Rng;
-use std::ops::Range;
+use broadphase_experiments::broadphase::Broadphase;
+use broadphase_experiments::diff::PairDiff;
+use broadphase_experiments::position::{new_fixed_vec, EntityPos, AABB};
+use broadphase_experiments::scene::{Scene, SceneConfig};
+use broadphase_experiments::{brute_force, bvh2, bvh3, bvh4, bvh5, dynamic, homemade, static_grid};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::ops::{Range, RangeInclusive};
+use std::process::exit;
 use std::time::{Duration, Instant};
 
-mod bvh2;
-mod bvh3;
-mod bvh4;
-mod bvh5;
-mod homemade;
-mod morton;
-mod position;
-mod static_grid;
-//mod bvh6;
-
-const RANGE: Range<i32> = -10_000..10_000;
-const ENTITY_COUNT: usize = 10_000;
-const BOX_HALF_SIZE: i32 = 50;
-
-fn random_pos() -> EntityPos {
-    let mut rng = rand::thread_rng();
-    EntityPos::from_primitives(
-        rng.gen_range(RANGE.clone()),
-        rng.gen_range(RANGE.clone()),
-        rng.gen_range(RANGE.clone()),
-    )
+const USAGE: &str = "usage: broadphase_experiments [options]
+    --structures <list>   comma separated list among brute-force, bvh2, bvh3, bvh4, bvh4-sah, bvh5, dynamic, morton,
+                          grid
+                          (default: every working structure, brute-force only up to 10000 entities)
+    --scene <name>        how the boxes are spread, among uniform, clusters, layers, corridors, mixed-sizes, coincident
+                          (default: uniform)
+    --count <n>           number of entities (default: 10000)
+    --range <min..max>    world size on each axis (default: -10000..10000)
+    --half-size <n|a..b>  half size of the boxes, fixed or uniformly picked in [a, b] for each axis (default: 50)
+    --threads <n>         number of rayon threads, 0 lets rayon decide (default: 0)
+    --repeat <n>          how many times each build and query is run, the average time is printed (default: 1)
+    --speed <n>           how far each entity can move on each axis before the next tick, to compare a full build
+                          with a rebuild reusing the previous tick (default: 0.1)
+    --seed <n>            seed of the scene (default: random)
+    --help                print this message";
+
+const DEFAULT_STRUCTURES: [&str; 9] = ["brute-force", "bvh2", "bvh3", "bvh4", "bvh4-sah", "bvh5", "dynamic", "morton", "grid"];
+
+struct Options {
+    structures: Vec<String>,
+    scene: Scene,
+    count: usize,
+    range: Range<i32>,
+    half_size: RangeInclusive<i32>,
+    threads: usize,
+    repeat: u32,
+    speed: f64,
+    seed: u64,
 }
 
-fn main() {
-    let leaves: Vec<AABB> = (0..ENTITY_COUNT)
-        .map(|_| {
-            let pos = random_pos();
-            let aabb = AABB::from_center(
-                pos,
-                new_fixed_vec(BOX_HALF_SIZE, BOX_HALF_SIZE, BOX_HALF_SIZE),
-            );
-            aabb
-        })
-        .collect();
-
-    rayon::ThreadPoolBuilder::new().build_global().unwrap();
-
-    println!("world size: {:?}", RANGE);
-    println!("entity count: {}", leaves.len());
-    println!("box half size: {}", BOX_HALF_SIZE);
-    println!("num_thread: {}", rayon::current_num_threads());
-
-    /*
-    //-- dummy way to test all collisions
-    if ENTITY_COUNT <= 10_000 {
-        println!("------------------------------------");
-        let time = Instant::now();
-        let mut collisions = 0;
-        for (i, aabb1) in leaves.iter().enumerate() {
-            for aabb2 in leaves[i + 1..].iter() {
-                if aabb1.intersects(aabb2) {
-                    collisions += 1;
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self {
+            structures: Vec::new(),
+            scene: Scene::Uniform,
+            count: 10_000,
+            range: -10_000..10_000,
+            half_size: 50..=50,
+            threads: 0,
+            repeat: 1,
+            speed: 0.1,
+            seed: rand::thread_rng().gen(),
+        };
+
+        while let Some(arg) = args.next() {
+            if arg == "--help" {
+                println!("{USAGE}");
+                exit(0);
+            }
+            let value = args.next().ok_or_else(|| format!("missing value after {arg}"))?;
+            match arg.as_str() {
+                "--structures" => options.structures = value.split(',').map(str::to_string).collect(),
+                "--scene" => options.scene = Scene::from_name(&value).ok_or_else(|| format!("unknown scene {value}"))?,
+                "--count" => options.count = parse_number(&arg, &value)?,
+                "--range" => {
+                    let (min, max) = parse_range(&arg, &value)?;
+                    if min >= max {
+                        return Err(format!("{arg} must not be empty"));
+                    }
+                    options.range = min..max;
+                }
+                "--half-size" => {
+                    let (min, max) = if value.contains("..") {
+                        parse_range(&arg, &value)?
+                    } else {
+                        let size = parse_number(&arg, &value)?;
+                        (size, size)
+                    };
+                    if min < 0 || min > max {
+                        return Err(format!("{arg} must be a positive, non empty range"));
+                    }
+                    options.half_size = min..=max;
                 }
+                "--threads" => options.threads = parse_number(&arg, &value)?,
+                "--repeat" => options.repeat = parse_number::<u32>(&arg, &value)?.max(1),
+                "--speed" => {
+                    options.speed = parse_number(&arg, &value)?;
+                    if !options.speed.is_finite() || options.speed < 0.0 {
+                        return Err(format!("{arg} must be a positive number"));
+                    }
+                }
+                "--seed" => options.seed = parse_number(&arg, &value)?,
+                _ => return Err(format!("unknown option {arg}")),
             }
         }
-        let elapsed = time.elapsed();
-        println!("collisions: {collisions} in {:?} with dummy way", elapsed);
+
+        if options.structures.is_empty() {
+            options.structures = DEFAULT_STRUCTURES.iter().map(|name| name.to_string()).collect();
+            if options.count > 10_000 { //the dummy way is O(n²)
+                options.structures.remove(0);
+            }
+        }
+        Ok(options)
     }
+}
 
+fn parse_number<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value {value} for {arg}"))
+}
 
-    //-- BVH2 way
-    println!("------------------------------------");
-    let mut bvh2 = bvh2::BVH::new();
-    let clone = leaves.clone();
-
-    let time = Instant::now();
-    bvh2.build(clone);
-    let elapsed = time.elapsed();
-    println!("bvh2 build in {:?}", elapsed);
-
-    let time = Instant::now();
-    let bvh_collisions = bvh2.get_collision();
-    let elapsed2 = time.elapsed();
-    println!("collisions: {bvh_collisions} in {:?} with BVH", elapsed2);
-    println!("total time: {:?}", elapsed + elapsed2);
-
-    //-- BVH3 way
-    println!("------------------------------------");
-    let mut bvh3 = bvh3::BVH::new();
-    let clone = leaves.clone();
-
-    let time = Instant::now();
-    bvh3.build(clone);
-    let elapsed = time.elapsed();
-    println!("bvh3 build in {:?}", elapsed);
-
-    let time = Instant::now();
-    let bvh3_collisions = bvh3.get_collision_par();
-    let elapsed2 = time.elapsed();
-    println!("collisions: {bvh3_collisions} in {:?} with BVH3", elapsed2);
-    println!("total time: {:?}", elapsed + elapsed2);
-
-    let time = Instant::now();
-    let collisions = bvh3.get_collision();
-    let elapsed = time.elapsed();
-    println!("collisions: {collisions} in {:?} with BVH3 recur", elapsed);
-
-    //-- BVH4 way
-    println!("------------------------------------");
-    let mut bvh4 = bvh4::BVH::new();
-    let clone = leaves.clone();
-
-    let time = Instant::now();
-    bvh4.build(clone);
-    let elapsed = time.elapsed();
-    println!("bvh4 build in {:?}", elapsed);
-
-    let time = Instant::now();
-    let bvh4_collisions = bvh4.get_collision_recursive();
-    let elapsed2 = time.elapsed();
-    println!("collisions: {bvh4_collisions} in {:?} with BVH4", elapsed2);
-    println!("total time: {:?}", elapsed + elapsed2); */
-
-    //-- BVH4 par way
-    /*println!("------------------------------------");
-    let mut bvh4 = bvh4::BVH::new();
-    let clone = leaves.clone();
-
-    let time = Instant::now();
-    bvh4.build_par(clone);
-    let elapsed = time.elapsed();
-    println!("bvh4 build par in {:?}", elapsed);
-
-    let time = Instant::now();
-    let bvh4_collisions = bvh4.get_collision_par();
-    let elapsed2 = time.elapsed();
-    println!("collisions: {bvh4_collisions} in {:?} with BVH4", elapsed2);
-    let total = elapsed + elapsed2;
-    println!("total time: {:?}", total); */
-
-    //-- BVH5 way
-    println!("------------------------------------");
-    let mut bvh5 = bvh5::BVH::new();
-    let clone = leaves.clone();
-
-    let time = Instant::now();
-    bvh5.build(clone);
-    let elapsed = time.elapsed();
-    println!("bvh5 build in {:?}", elapsed);
-
-    /*
-
-    let time = Instant::now();
-    let bvh5_collisions = bvh5.get_collision_par();
-    let elapsed2 = time.elapsed();
-    println!("collisions: {bvh5_collisions} in {:?} with BVH5", elapsed2);
-    println!("total time: {:?}", elapsed + elapsed2);
-
-    let time = Instant::now();
-    let bvh5_collisions = bvh5.get_collision_rev_par();
-    let elapsed2 = time.elapsed();
-    println!(
-        "collisions: {bvh5_collisions} in {:?} with BVH5 rev",
-        elapsed2
-    );
-    println!("total time: {:?}", elapsed + elapsed2);*/
-
-
-    let mut forward = Duration::ZERO;
-    let mut backward = Duration::ZERO;
-
-    for _ in 0..50 {
-        let time = Instant::now();
-        let bvh5_collisions = bvh5.get_collision_par();
-        let elapsed2 = time.elapsed();
-        forward += elapsed2;
-    }
-    for _ in 0..50 {
-        let time = Instant::now();
-        let bvh5_collisions = bvh5.get_collision_rev_par();
-        let elapsed2 = time.elapsed();
-        backward += elapsed2;
-    }
+fn parse_range(arg: &str, value: &str) -> Result<(i32, i32), String> {
+    let (min, max) = value.split_once("..").ok_or_else(|| format!("{arg} expects a range like min..max"))?;
+    Ok((parse_number(arg, min)?, parse_number(arg, max)?))
+}
 
-    println!("forward: {:?}", forward/50);
-    println!("backward: {:?}", backward/50);
+fn make_structure(name: &str) -> Option<Box<dyn Broadphase>> {
+    let structure: Box<dyn Broadphase> = match name {
+        "brute-force" => Box::new(brute_force::BruteForce::new()),
+        "bvh2" => Box::new(bvh2::BVH::new()),
+        "bvh3" => Box::new(bvh3::BVH::new()),
+        "bvh4" => Box::new(bvh4::BVH::new()),
+        "bvh4-sah" => Box::new(bvh4::BVH::with_builder(bvh4::Builder::BinnedSah)),
+        "bvh5" => Box::new(bvh5::BVH::new()),
+        "dynamic" => Box::new(dynamic::DynamicTree::new()),
+        "morton" => Box::new(homemade::MortonList::new()),
+        "grid" => Box::new(static_grid::GridTracker::new()),
+        _ => return None,
+    };
+    Some(structure)
+}
 
+///run `f` `repeat` times and return the last result with the average time, `setup` isn't timed
+fn timed<I, R>(repeat: u32, mut setup: impl FnMut() -> I, mut f: impl FnMut(I) -> R) -> (R, Duration) {
+    let mut total = Duration::ZERO;
+    let mut result = None;
+    for _ in 0..repeat {
+        let input = setup();
+        let time = Instant::now();
+        result = Some(f(input));
+        total += time.elapsed();
+    }
+    (result.unwrap(), total / repeat)
+}
 
-    /* both are broken and give wrong results
-    //-- homemade way
-    println!("------------------------------------");
-    let mut morton_list = homemade::MortonList::new();
-    let clone = leaves.clone();
+fn main() {
+    let options = Options::parse(std::env::args().skip(1)).unwrap_or_else(|error| {
+        eprintln!("{error}\n{USAGE}");
+        exit(1);
+    });
+    let structures: Vec<Box<dyn Broadphase>> = options.structures.iter().map(|name| {
+        make_structure(name).unwrap_or_else(|| {
+            eprintln!("unknown structure {name}\n{USAGE}");
+            exit(1);
+        })
+    }).collect();
+
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let config = SceneConfig {
+        count: options.count,
+        range: options.range.clone(),
+        half_size: options.half_size.clone(),
+    };
+    let leaves: Vec<AABB> = options.scene.generate(&mut rng, &config);
+    let next_tick: Vec<AABB> = leaves.iter().map(|aabb| {
+        let displacement: EntityPos = if options.speed == 0.0 {
+            EntityPos::zeros()
+        } else {
+            new_fixed_vec(rng.gen_range(-options.speed..=options.speed), rng.gen_range(-options.speed..=options.speed), rng.gen_range(-options.speed..=options.speed))
+        };
+        AABB::new(aabb.min() + displacement, aabb.max() + displacement)
+    }).collect();
+
+    rayon::ThreadPoolBuilder::new().num_threads(options.threads).build_global().unwrap();
+
+    println!("scene: {}", options.scene.name());
+    println!("world size: {:?}", options.range);
+    println!("entity count: {}", leaves.len());
+    println!("box half size: {:?}", options.half_size);
+    println!("num_thread: {}", rayon::current_num_threads());
+    println!("speed: {}", options.speed);
+    println!("seed: {}", options.seed);
 
-    let time = Instant::now();
-    morton_list.build(clone);
-    let elapsed = time.elapsed();
-    println!("morton-based enclosing build in {:?}", elapsed);
+    //the first structure is the reference, when another one disagrees with it we print which pairs differ
+    let mut reference: Option<(usize, Box<dyn Broadphase>)> = None;
 
-    let time = Instant::now();
-    let homemade_collisions = morton_list.get_collisions();
-    let elapsed2 = time.elapsed();
-    println!("collisions: {homemade_collisions} in {:?} with morton-based enclosing", elapsed2);
-    println!("total time: {:?}", elapsed + elapsed2);
+    for mut structure in structures {
+        let name = structure.name();
 
-    //-- Static grid way
-    println!("------------------------------------");
-    println!("grid size: {:?}", static_grid::CELL_SIZE);
-    let mut grid = static_grid::GridTracker::new();
-    let clone = leaves.clone();
+        println!("------------------------------------");
+        let ((), elapsed) = timed(options.repeat, || leaves.clone(), |clone| structure.build(clone));
+        println!("{name} build in {:?}", elapsed);
+
+        let (collisions, elapsed2) = timed(options.repeat, || (), |()| structure.get_collision());
+        println!("collisions: {collisions} in {:?} with {name}", elapsed2);
+        println!("total time: {:?}", elapsed + elapsed2);
+
+        let ((), elapsed) = timed(options.repeat, || leaves.clone(), |clone| structure.build_par(clone));
+        println!("{name} build par in {:?}", elapsed);
+
+        let (collisions, elapsed2) = timed(options.repeat, || (), |()| structure.get_collision_par());
+        println!("collisions: {collisions} in {:?} with {name} par", elapsed2);
+        println!("total time: {:?}", elapsed + elapsed2);
+
+        let ((), elapsed) = timed(options.repeat, || next_tick.clone(), |clone| structure.build_par(clone));
+        println!("{name} build par of the next tick in {:?}", elapsed);
+        let mut total = Duration::ZERO;
+        for _ in 0..options.repeat {
+            structure.rebuild(leaves.clone());
+            let clone = next_tick.clone();
+            let time = Instant::now();
+            structure.rebuild(clone);
+            total += time.elapsed();
+        }
+        println!("{name} rebuild of the next tick in {:?}", total / options.repeat);
+        structure.build_par(leaves.clone()); //back to the first tick for the comparison
 
-    let time = Instant::now();
-    for aabb in clone {
-        grid.insert(aabb);
+        match &reference {
+            None => reference = Some((collisions, structure)),
+            Some((expected, reference)) if *expected != collisions => {
+                PairDiff::new(reference.as_ref(), structure.as_ref()).print(reference.as_ref(), structure.as_ref(), &leaves);
+            }
+            Some(_) => {}
+        }
     }
-    let elapsed = time.elapsed();
-    println!("grid build in {:?}", elapsed);
-
-    let time = Instant::now();
-    let grid_collisions = grid.get_collisions();
-    let elapsed2 = time.elapsed();
-    println!("collisions: {grid_collisions} in {:?} with grid", elapsed2);
-    println!("total time: {:?}", elapsed + elapsed2);*/
 }
@@ -0,0 +1,129 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use fixed::types::{I32F32, I64F64};
+use crate::hierarchy::Hierarchy;
+use crate::position::{EntityPos, AABB};
+
+///squared distance between the point and the closest point of the box, 0 inside the box
+///computed in I64F64 so squaring a coordinate can't overflow, it only saturates for points billions of blocks away
+pub fn distance_squared(aabb: &AABB, point: &EntityPos) -> I64F64 {
+    let mut total = I64F64::ZERO;
+    for axis in 0..3 {
+        let p = I64F64::from_num(point[axis]);
+        let gap = (I64F64::from_num(aabb.min()[axis]) - p).max(p - I64F64::from_num(aabb.max()[axis])).max(I64F64::ZERO);
+        total = total.saturating_add(gap.saturating_mul(gap));
+    }
+    total
+}
+
+///the `k` entities closest to `point` with their squared distance, sorted by distance then by entity index
+///best first search, nodes are visited in order of distance until k leaves came out of the queue
+///a `k` past the entity count, like usize::MAX, gives every entity
+pub fn nearest(tree: &(impl Hierarchy + ?Sized), point: &EntityPos, k: usize) -> Vec<(usize, I64F64)> {
+    let mut found = Vec::new(); //not sized from k, which can mean "all"
+
+    let Some(root) = tree.root() else { return found; };
+    if k == 0 {
+        return found;
+    }
+
+    //a branch comes out before a leaf at the same distance, since it may hold a leaf with a lower index at that distance
+    let mut queue = BinaryHeap::new();
+    let push = |queue: &mut BinaryHeap<Reverse<(I64F64, bool, usize)>>, node: usize| {
+        let distance = distance_squared(tree.node_aabb(node), point);
+        match tree.children(node) {
+            Some(_) => queue.push(Reverse((distance, false, node))),
+            None => queue.push(Reverse((distance, true, tree.entity(node)))),
+        }
+    };
+    push(&mut queue, root);
+
+    while let Some(Reverse((distance, is_leaf, index))) = queue.pop() {
+        if is_leaf {
+            found.push((index, distance));
+            if found.len() == k {
+                break;
+            }
+        } else {
+            let (left, right) = tree.children(index).unwrap();
+            push(&mut queue, left);
+            push(&mut queue, right);
+        }
+    }
+    found
+}
+
+///every entity whose box is at most `radius` away from `point`, with its squared distance, sorted like `nearest`
+pub fn within_radius(tree: &(impl Hierarchy + ?Sized), point: &EntityPos, radius: I32F32) -> Vec<(usize, I64F64)> {
+    assert!(radius >= I32F32::ZERO);
+    let radius = I64F64::from_num(radius);
+    let radius_squared = radius.saturating_mul(radius);
+    let mut found = Vec::new();
+    let mut stack: Vec<usize> = tree.root().into_iter().collect();
+    while let Some(node) = stack.pop() {
+        let distance = distance_squared(tree.node_aabb(node), point);
+        if distance > radius_squared { continue; }
+        match tree.children(node) {
+            None => found.push((tree.entity(node), distance)),
+            Some((left, right)) => {
+                stack.push(right);
+                stack.push(left);
+            }
+        }
+    }
+    found.sort_unstable_by_key(|&(entity, distance)| (distance, entity));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::brute_force::testing::{random_coord, random_scene, LEAF_COUNTS, SEEDS};
+    use crate::position::new_fixed_vec;
+    use crate::{bvh4, bvh5};
+
+    #[test]
+    fn unbounded_k_gives_every_entity() {
+        let mut tree = bvh5::BVH::new();
+        tree.build((0..10).map(|i| AABB::from_center(new_fixed_vec(i, 0, 0), new_fixed_vec(0, 0, 0))).collect());
+        let found = nearest(&tree, &new_fixed_vec(0, 0, 0), usize::MAX);
+        assert_eq!(found.iter().map(|&(entity, _)| entity).collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+    }
+
+    fn check_nearest(tree: &impl Hierarchy, leaves: &[AABB], points: &[EntityPos], context: &str) {
+        for point in points {
+            let mut expected: Vec<(usize, I64F64)> = leaves.iter().enumerate().map(|(i, aabb)| (i, distance_squared(aabb, point))).collect();
+            expected.sort_unstable_by_key(|&(entity, distance)| (distance, entity));
+            for k in [0, 1, 2, 5, leaves.len(), leaves.len() + 1] {
+                assert_eq!(nearest(tree, point, k), expected[..k.min(leaves.len())], "{context}: wrong {k} nearest to {point:?}");
+            }
+            for radius in [0, 1, 3, 10] {
+                let radius = I32F32::from_num(radius);
+                let inside: Vec<_> = expected.iter().copied().filter(|&(_, distance)| distance <= I64F64::from_num(radius) * I64F64::from_num(radius)).collect();
+                assert_eq!(within_radius(tree, point, radius), inside, "{context}: wrong entities within {radius} of {point:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn queries_match_oracle() {
+        let mut bvh4 = bvh4::BVH::new();
+        let mut bvh5 = bvh5::BVH::new();
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for count in LEAF_COUNTS {
+                let leaves = random_scene(&mut rng, count);
+                let points: Vec<EntityPos> = (0..8).map(|_| {
+                    let integer = rng.gen_bool(0.5);
+                    new_fixed_vec(random_coord(&mut rng, 40, integer), random_coord(&mut rng, 40, integer), random_coord(&mut rng, 40, integer))
+                }).collect();
+                bvh4.build(leaves.clone());
+                check_nearest(&bvh4, &leaves, &points, &format!("bvh4 with seed {seed} and {count} leaves"));
+                bvh5.build(leaves.clone());
+                check_nearest(&bvh5, &leaves, &points, &format!("bvh5 with seed {seed} and {count} leaves"));
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use fixed::traits::{Fixed, ToFixed};
-use fixed::types::I32F32;
+use fixed::types::{I32F32, I64F64};
 use nalgebra::Vector3;
 
 pub type EntityPos = Vector3<I32F32>;
@@ -73,5 +73,32 @@ impl AABB {
         Self::new(min, max)
     }
 
+    pub fn contains(&self, other: &Self) -> bool {
+        self.min().x <= other.min().x && other.max().x <= self.max().x &&
+            self.min().y <= other.min().y && other.max().y <= self.max().y &&
+            self.min().z <= other.min().z && other.max().z <= self.max().z
+    }
+
+    ///grown by `margin` on every side, then stretched toward `displacement`, so it still holds the box after small moves
+    pub fn fattened(&self, margin: I32F32, displacement: &EntityPos) -> Self {
+        let mut min = self.min.map(|x| x.saturating_sub(margin));
+        let mut max = self.max.map(|x| x.saturating_add(margin));
+        for axis in 0..3 {
+            if displacement[axis] < I32F32::ZERO {
+                min[axis] = min[axis].saturating_add(displacement[axis]);
+            } else {
+                max[axis] = max[axis].saturating_add(displacement[axis]);
+            }
+        }
+        Self::new(min, max)
+    }
+
+    ///used as the cost of a node by the surface area heuristic, in I64F64 since the products overflow an I32F32
+    pub fn surface_area(&self) -> I64F64 {
+        let size = (self.max - self.min).map(I64F64::from_num);
+        let half = size.x.saturating_mul(size.y).saturating_add(size.y.saturating_mul(size.z)).saturating_add(size.z.saturating_mul(size.x));
+        half.saturating_mul(I64F64::from_num(2))
+    }
+
 }
 
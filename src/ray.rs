@@ -0,0 +1,233 @@
+use fixed::types::I32F32;
+use crate::hierarchy::Hierarchy;
+use crate::position::{EntityPos, AABB};
+
+///half line starting at `origin`, cut at `max_distance`
+///everything is computed in fixed point, so a cast gives the same result on every machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    origin: EntityPos,
+    direction: EntityPos, //the slab test works in multiples of this vector
+    max_step: I32F32, //how many times `direction` can be walked
+    step_length: I32F32, //length of `direction`, to turn steps back into distances
+}
+
+impl Ray {
+    ///the direction doesn't need to be normalized, a null direction only hits the boxes holding the origin
+    pub fn new(origin: EntityPos, direction: EntityPos, max_distance: I32F32) -> Self {
+        assert!(max_distance >= I32F32::ZERO);
+        Self {
+            origin,
+            direction: normalize(direction).0,
+            max_step: max_distance,
+            step_length: I32F32::ONE,
+        }
+    }
+
+    ///from `start` to `end`, both included
+    ///the direction isn't normalized, so `end` is exactly one step away and the rounding can't make it miss
+    pub fn segment(start: EntityPos, end: EntityPos) -> Self {
+        let direction = end.zip_map(&start, |a, b| a.saturating_sub(b));
+        Self {
+            origin: start,
+            direction,
+            max_step: I32F32::ONE,
+            step_length: normalize(direction).1,
+        }
+    }
+
+    pub fn origin(&self) -> &EntityPos {
+        &self.origin
+    }
+
+    ///normalized for `new`, from start to end for `segment`
+    pub fn direction(&self) -> &EntityPos {
+        &self.direction
+    }
+
+    pub fn max_distance(&self) -> I32F32 {
+        self.max_step.saturating_mul(self.step_length)
+    }
+
+    ///slab test, distance at which the ray enters the box, 0 when the origin is inside
+    ///None if the ray misses the box or only reaches it after `max_distance`
+    pub fn intersect(&self, aabb: &AABB) -> Option<I32F32> {
+        let mut near = I32F32::ZERO;
+        let mut far = self.max_step;
+        for axis in 0..3 {
+            let origin = self.origin[axis];
+            let direction = self.direction[axis];
+            let (min, max) = (aabb.min()[axis], aabb.max()[axis]);
+            if direction == I32F32::ZERO { //parallel to the slab, either always in it or never
+                if origin < min || origin > max {
+                    return None;
+                }
+                continue;
+            }
+            //the division saturates instead of overflowing for tiny directions, the hit is then simply very far
+            let t1 = min.saturating_sub(origin).saturating_div(direction);
+            let t2 = max.saturating_sub(origin).saturating_div(direction);
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+            if near > far {
+                return None;
+            }
+        }
+        Some(near.saturating_mul(self.step_length))
+    }
+
+    ///first entity found on the ray, not necessarily the closest one, enough for line of sight checks
+    pub fn cast_any(&self, tree: &(impl Hierarchy + ?Sized)) -> Option<usize> {
+        let mut stack: Vec<usize> = tree.root().into_iter().collect();
+        while let Some(node) = stack.pop() {
+            if self.intersect(tree.node_aabb(node)).is_none() { continue; }
+            match tree.children(node) {
+                None => return Some(tree.entity(node)),
+                Some((left, right)) => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        None
+    }
+
+    ///closest entity hit and the distance of the hit, when two hits are as close the lowest entity index wins
+    pub fn cast_closest(&self, tree: &(impl Hierarchy + ?Sized)) -> Option<(usize, I32F32)> {
+        let mut best: Option<(usize, I32F32)> = None;
+        let mut stack: Vec<(usize, I32F32)> = tree.root().and_then(|root| Some((root, self.intersect(tree.node_aabb(root))?))).into_iter().collect();
+        while let Some((node, distance)) = stack.pop() {
+            if best.is_some_and(|(_, best_distance)| distance > best_distance) { continue; } //found something closer since it was pushed
+            match tree.children(node) {
+                None => {
+                    let entity = tree.entity(node);
+                    if best.is_none_or(|(best_entity, best_distance)| (distance, entity) < (best_distance, best_entity)) {
+                        best = Some((entity, distance));
+                    }
+                }
+                Some((left, right)) => {
+                    let left = self.intersect(tree.node_aabb(left)).map(|distance| (left, distance));
+                    let right = self.intersect(tree.node_aabb(right)).map(|distance| (right, distance));
+                    let (near, far) = match (left, right) {
+                        (Some(left), Some(right)) if right.1 < left.1 => (Some(right), Some(left)),
+                        other => other,
+                    };
+                    stack.extend(far); //the nearest child is popped first, so the farthest one is often pruned
+                    stack.extend(near);
+                }
+            }
+        }
+        best
+    }
+
+    ///every entity hit with the distance of the hit, sorted by distance then by entity index
+    pub fn cast_all(&self, tree: &(impl Hierarchy + ?Sized)) -> Vec<(usize, I32F32)> {
+        let mut hits = Vec::new();
+        let mut stack: Vec<usize> = tree.root().into_iter().collect();
+        while let Some(node) = stack.pop() {
+            let Some(distance) = self.intersect(tree.node_aabb(node)) else { continue; };
+            match tree.children(node) {
+                None => hits.push((tree.entity(node), distance)),
+                Some((left, right)) => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        hits.sort_unstable_by_key(|&(entity, distance)| (distance, entity));
+        hits
+    }
+}
+
+///unit vector and length, computed after dividing by the largest component so the squares can't overflow
+fn normalize(vector: EntityPos) -> (EntityPos, I32F32) {
+    let largest = vector.iter().map(|x| x.saturating_abs()).max().unwrap();
+    if largest == I32F32::ZERO {
+        return (vector, I32F32::ZERO);
+    }
+    let scaled = vector.map(|x| x / largest);
+    let length = (scaled.x * scaled.x + scaled.y * scaled.y + scaled.z * scaled.z).sqrt(); //between 1 and sqrt(3)
+    (scaled.map(|x| x / length), largest.saturating_mul(length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::brute_force::testing::{random_coord, random_scene, LEAF_COUNTS, SEEDS};
+    use crate::position::new_fixed_vec;
+    use crate::{bvh4, bvh5};
+
+    fn unit_box(x: i32, y: i32, z: i32) -> AABB {
+        AABB::from_center(new_fixed_vec(x, y, z), new_fixed_vec(1, 1, 1))
+    }
+
+    #[test]
+    fn slab_distances() {
+        let ray = Ray::new(new_fixed_vec(0, 0, 0), new_fixed_vec(10, 0, 0), I32F32::from_num(100));
+        assert_eq!(ray.intersect(&unit_box(5, 0, 0)), Some(I32F32::from_num(4)));
+        assert_eq!(ray.intersect(&unit_box(0, 0, 0)), Some(I32F32::ZERO), "the origin is inside");
+        assert_eq!(ray.intersect(&unit_box(-5, 0, 0)), None, "behind the ray");
+        assert_eq!(ray.intersect(&unit_box(5, 3, 0)), None, "beside the ray");
+        assert_eq!(ray.intersect(&unit_box(5, 1, 0)), Some(I32F32::from_num(4)), "touching the face");
+        assert_eq!(ray.intersect(&unit_box(200, 0, 0)), None, "too far");
+    }
+
+    #[test]
+    fn segment_stops_at_its_end() {
+        let segment = Ray::segment(new_fixed_vec(0, 0, 0), new_fixed_vec(3, 4, 0));
+        assert_eq!(segment.max_distance(), I32F32::from_num(5));
+        assert!(segment.intersect(&AABB::from_center(new_fixed_vec(3, 4, 0), new_fixed_vec(0, 0, 0))).is_some());
+        assert!(segment.intersect(&unit_box(6, 8, 0)).is_none());
+    }
+
+    #[test]
+    fn huge_and_null_directions() {
+        let ray = Ray::new(new_fixed_vec(0, 0, 0), new_fixed_vec(i32::MAX, i32::MIN, 0), I32F32::MAX);
+        assert!(ray.intersect(&unit_box(1000, -1000, 0)).is_some());
+        let ray = Ray::new(new_fixed_vec(0, 0, 0), new_fixed_vec(0, 0, 0), I32F32::MAX);
+        assert_eq!(ray.intersect(&unit_box(0, 0, 0)), Some(I32F32::ZERO));
+        assert_eq!(ray.intersect(&unit_box(5, 0, 0)), None);
+    }
+
+    ///casts every ray against each leaf, and checks the three modes of the tree against it
+    fn check_ray_casts(tree: &impl Hierarchy, leaves: &[AABB], rays: &[Ray], context: &str) {
+        for ray in rays {
+            let mut expected: Vec<(usize, I32F32)> = leaves.iter().enumerate().filter_map(|(i, aabb)| Some((i, ray.intersect(aabb)?))).collect();
+            expected.sort_unstable_by_key(|&(entity, distance)| (distance, entity));
+            assert_eq!(ray.cast_all(tree), expected, "{context}: wrong hits for {ray:?}");
+            assert_eq!(ray.cast_closest(tree), expected.first().copied(), "{context}: wrong closest hit for {ray:?}");
+            match ray.cast_any(tree) {
+                Some(entity) => assert!(expected.iter().any(|&(hit, _)| hit == entity), "{context}: {entity} isn't on {ray:?}"),
+                None => assert!(expected.is_empty(), "{context}: missed every hit of {ray:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn casts_match_oracle() {
+        let mut bvh4 = bvh4::BVH::new();
+        let mut bvh5 = bvh5::BVH::new();
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for count in LEAF_COUNTS {
+                let leaves = random_scene(&mut rng, count);
+                let rays: Vec<Ray> = (0..8).map(|_| {
+                    let origin = new_fixed_vec(random_coord(&mut rng, 40, false), random_coord(&mut rng, 40, false), random_coord(&mut rng, 40, false));
+                    let end: EntityPos = new_fixed_vec(random_coord(&mut rng, 40, true), random_coord(&mut rng, 40, true), random_coord(&mut rng, 40, true));
+                    if rng.gen_bool(0.5) {
+                        Ray::segment(origin, end)
+                    } else { //directions along the axes are common, so some components are often null
+                        let direction = new_fixed_vec(rng.gen_range(-1..=1), rng.gen_range(-1..=1), rng.gen_range(-1..=1));
+                        Ray::new(origin, direction, I32F32::from_num(rng.gen_range(0..200)))
+                    }
+                }).collect();
+                bvh4.build(leaves.clone());
+                check_ray_casts(&bvh4, &leaves, &rays, &format!("bvh4 with seed {seed} and {count} leaves"));
+                bvh5.build(leaves.clone());
+                check_ray_casts(&bvh5, &leaves, &rays, &format!("bvh5 with seed {seed} and {count} leaves"));
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use fixed::types::{I32F32, I64F64};
+use rayon::prelude::*;
+use crate::bvh4::{Node, NodeKind};
+use crate::position::{EntityPos, AABB};
+
+///how many buckets the centers are sorted into on each axis, the split is only searched between buckets
+const BINS: usize = 16;
+///below this many entities a node is built on the current thread, splitting it further costs more than it saves
+const PARALLEL_THRESHOLD: usize = 4096;
+///up to this many entities every split position is tried, cheaper than filling the bins, and exact
+const SWEEP_THRESHOLD: usize = BINS;
+
+#[derive(Clone, Copy)]
+struct Bin {
+    aabb: Option<AABB>,
+    count: usize,
+}
+
+impl Bin {
+    const EMPTY: Self = Self { aabb: None, count: 0 };
+
+    fn add(&mut self, aabb: &AABB) {
+        self.aabb = Some(self.aabb.map_or(*aabb, |bin| bin.union(aabb)));
+        self.count += 1;
+    }
+
+    fn merge(&self, other: &Self) -> Self {
+        let aabb = match (self.aabb, other.aabb) {
+            (Some(a), Some(b)) => Some(a.union(&b)),
+            (a, b) => a.or(b),
+        };
+        Self { aabb, count: self.count + other.count }
+    }
+
+    ///surface area heuristic cost of a child holding the entities of this bin
+    fn cost(&self) -> I64F64 {
+        self.aabb.map_or(I64F64::ZERO, |aabb| aabb.surface_area().saturating_mul(I64F64::from_num(self.count)))
+    }
+}
+
+///nodes in the `bvh4` layout, the leaves first then the branches, each branch after its children so the root is last
+///the tree is split top down, each node where the sum of the children's surface area times their entity count is the lowest
+pub(crate) fn build_nodes(leaves: &[AABB]) -> Vec<Node> {
+    let len = leaves.len();
+    if len == 0 {
+        return Vec::new();
+    }
+    let mut order: Vec<usize> = (0..len).collect();
+    let centers: Vec<EntityPos> = leaves.par_iter().map(AABB::center).collect();
+    let mut branches: Vec<Node> = (1..len).map(|_| Node { aabb: AABB::empty(), kind: NodeKind::Leaf(0) }).collect();
+    build_node(leaves, &centers, &mut order, 0, &mut branches, len);
+
+    let mut nodes = Vec::with_capacity(2 * len - 1);
+    order.into_par_iter().map(|i| Node { aabb: leaves[i], kind: NodeKind::Leaf(i) }).collect_into_vec(&mut nodes);
+    nodes.append(&mut branches);
+    nodes
+}
+
+///the entities of `order` become the leaves `first_leaf..`, and their `order.len() - 1` branches fill `branches`, starting at `first_branch`
+///the left child takes the first branches and the right child the next ones, so the subtrees never write to the same place
+fn build_node(leaves: &[AABB], centers: &[EntityPos], order: &mut [usize], first_leaf: usize, branches: &mut [Node], first_branch: usize) -> (usize, AABB) {
+    let len = order.len();
+    if len == 1 {
+        return (first_leaf, leaves[order[0]]);
+    }
+    let mid = split(leaves, centers, order);
+    let (left_order, right_order) = order.split_at_mut(mid);
+    let (left_branches, rest) = branches.split_at_mut(mid - 1);
+    let (right_branches, root) = rest.split_at_mut(len - mid - 1);
+    let mut build_left = || build_node(leaves, centers, left_order, first_leaf, left_branches, first_branch);
+    let mut build_right = || build_node(leaves, centers, right_order, first_leaf + mid, right_branches, first_branch + mid - 1);
+    let ((left, left_aabb), (right, right_aabb)) = if len >= PARALLEL_THRESHOLD {
+        rayon::join(build_left, build_right)
+    } else {
+        (build_left(), build_right())
+    };
+    let aabb = left_aabb.union(&right_aabb);
+    root[0] = Node { aabb, kind: NodeKind::Branch(left, right) };
+    (first_branch + len - 2, aabb)
+}
+
+///reorder `order` so the entities of the best split come first, returns how many there are, always in 1..len
+fn split(leaves: &[AABB], centers: &[EntityPos], order: &mut [usize]) -> usize {
+    let len = order.len();
+    if len <= SWEEP_THRESHOLD {
+        return sweep_split(leaves, centers, order);
+    }
+    let union = |(min_a, max_a): (EntityPos, EntityPos), (min_b, max_b): (EntityPos, EntityPos)| {
+        (min_a.zip_map(&min_b, |a, b| a.min(b)), max_a.zip_map(&max_b, |a, b| a.max(b)))
+    };
+    let bounds = |bounds: (EntityPos, EntityPos), i: &usize| union(bounds, (centers[*i], centers[*i]));
+    let start = (centers[order[0]], centers[order[0]]);
+    let (min, max) = if len >= PARALLEL_THRESHOLD {
+        order.par_iter().fold(|| start, bounds).reduce(|| start, union)
+    } else {
+        order.iter().fold(start, bounds)
+    };
+    let extent = max - min;
+    if extent == EntityPos::zeros() { //every center at the same place, no split is better than another
+        return len / 2;
+    }
+
+    let scale = extent.map(|size| if size == I32F32::ZERO { 0.0 } else { BINS as f64 / size.to_num::<f64>() });
+    let bin_of = |axis: usize, i: usize| (((centers[i][axis] - min[axis]).to_num::<f64>() * scale[axis]) as usize).min(BINS - 1);
+    //the bins are too big to be passed around by value for each entity, so each chunk fills its own
+    let binned = |chunk: &[usize]| {
+        let mut bins = [[Bin::EMPTY; BINS]; 3];
+        for &i in chunk {
+            for (axis, axis_bins) in bins.iter_mut().enumerate() {
+                if extent[axis] > I32F32::ZERO {
+                    axis_bins[bin_of(axis, i)].add(&leaves[i]);
+                }
+            }
+        }
+        bins
+    };
+    let bins = if len >= PARALLEL_THRESHOLD {
+        order.par_chunks(PARALLEL_THRESHOLD).map(binned).reduce(|| [[Bin::EMPTY; BINS]; 3], |a, b| std::array::from_fn(|axis| std::array::from_fn(|bin| a[axis][bin].merge(&b[axis][bin]))))
+    } else {
+        binned(order)
+    };
+
+    //the cost of splitting after each bin, sweeping from both ends
+    let mut best: Option<(I64F64, usize, usize)> = None;
+    for (axis, axis_bins) in bins.iter().enumerate() {
+        if extent[axis] == I32F32::ZERO { continue; }
+        let mut right_costs = [I64F64::ZERO; BINS];
+        let mut right = Bin::EMPTY;
+        for bin in (1..BINS).rev() {
+            right = right.merge(&axis_bins[bin]);
+            right_costs[bin] = right.cost();
+        }
+        let mut left = Bin::EMPTY;
+        for bin in 0..BINS - 1 {
+            left = left.merge(&axis_bins[bin]);
+            if left.count == 0 || left.count == len { continue; }
+            let cost = left.cost().saturating_add(right_costs[bin + 1]);
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, bin));
+            }
+        }
+    }
+    let Some((_, axis, bin)) = best else { return len / 2; }; //every center of a bin on each axis, can't happen with a non null extent
+
+    let mut mid = 0;
+    for i in 0..len {
+        if bin_of(axis, order[i]) <= bin {
+            order.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+///the split of `split` for small nodes, the entities are sorted by center on each axis and every position is tried
+fn sweep_split(leaves: &[AABB], centers: &[EntityPos], order: &mut [usize]) -> usize {
+    let len = order.len();
+    let mut best: Option<(I64F64, usize, usize)> = None;
+    let mut sorted = [0; SWEEP_THRESHOLD];
+    let sorted = &mut sorted[..len];
+    for axis in [0, 1, 2] {
+        sorted.copy_from_slice(order);
+        sorted.sort_unstable_by_key(|&i| (centers[i][axis], i));
+        //right_costs[i] is the cost of a child holding the entities from i to the end
+        let mut right_costs = [I64F64::ZERO; SWEEP_THRESHOLD];
+        let mut right = Bin::EMPTY;
+        for i in (1..len).rev() {
+            right.add(&leaves[sorted[i]]);
+            right_costs[i] = right.cost();
+        }
+        let mut left = Bin::EMPTY;
+        for i in 0..len - 1 {
+            left.add(&leaves[sorted[i]]);
+            let cost = left.cost().saturating_add(right_costs[i + 1]);
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, i + 1));
+            }
+        }
+    }
+    let (_, axis, mid) = best.expect("a node has at least 2 entities");
+    order.sort_unstable_by_key(|&i| (centers[i][axis], i));
+    mid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::broadphase::Broadphase;
+    use crate::brute_force::normalize_pairs;
+    use crate::bvh4::{Builder, BVH};
+    use crate::position::new_fixed_vec;
+    use crate::scene::{Scene, SceneConfig};
+
+    #[test]
+    fn layout_matches_bvh4() {
+        for len in [1, 2, 3, 7, 100, 3 * PARALLEL_THRESHOLD] {
+            //two far groups, and a column of coincident centers
+            let leaves: Vec<AABB> = (0..len).map(|i| {
+                let center: EntityPos = match i % 3 {
+                    0 => new_fixed_vec(i, 0, 0),
+                    1 => new_fixed_vec(1000 + i, 5, 0),
+                    _ => new_fixed_vec(-50, -50, -50),
+                };
+                AABB::from_center(center, new_fixed_vec(1, 1, 1))
+            }).collect();
+            let nodes = build_nodes(&leaves);
+            assert_eq!(nodes.len(), 2 * len - 1);
+            let mut entities: Vec<usize> = nodes[..len].iter().map(|node| match node.kind {
+                NodeKind::Leaf(entity) => entity,
+                NodeKind::Branch(..) => panic!("a branch among the leaves"),
+            }).collect();
+            entities.sort_unstable();
+            assert_eq!(entities, (0..len).collect::<Vec<_>>());
+            let mut children = Vec::new();
+            for (index, node) in nodes.iter().enumerate().skip(len) {
+                let NodeKind::Branch(left, right) = node.kind else { panic!("a leaf among the branches") };
+                assert!(left < index && right < index, "children come before their parent");
+                assert_eq!(node.aabb, nodes[left].aabb.union(&nodes[right].aabb));
+                children.extend([left, right]);
+            }
+            children.sort_unstable();
+            assert_eq!(children, (0..2 * len - 2).collect::<Vec<_>>(), "every node but the root has one parent");
+        }
+    }
+
+    #[test]
+    fn parallel_build_finds_every_pair() {
+        let config = SceneConfig { count: 2 * PARALLEL_THRESHOLD, range: -200..200, half_size: 0..=3 };
+        for scene in Scene::ALL {
+            let leaves = scene.generate(&mut StdRng::seed_from_u64(0), &config);
+            let mut hilbert = BVH::new();
+            hilbert.build_par(leaves.clone());
+            let mut sah = BVH::with_builder(Builder::BinnedSah);
+            Broadphase::build(&mut sah, leaves);
+            assert_eq!(normalize_pairs(sah.get_collision_pairs_par()), normalize_pairs(hilbert.get_collision_pairs_par()), "{}", scene.name());
+        }
+    }
+}
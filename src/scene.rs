@@ -0,0 +1,132 @@
+use std::ops::{Range, RangeInclusive};
+use fixed::types::I32F32;
+use rand::Rng;
+use crate::position::{new_fixed_vec, EntityPos, AABB};
+
+///how the boxes of a benchmark or a test are spread in the world
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scene {
+    ///every box is picked uniformly in the world, on integer coordinates
+    Uniform,
+    ///dense clusters, like mob farms, most of the boxes are packed around a few points
+    Clusters,
+    ///boxes standing on a few flat layers, like entities on the terrain
+    Layers,
+    ///boxes in long and narrow corridors along the axes
+    Corridors,
+    ///a lot of tiny boxes and a few huge ones
+    MixedSizes,
+    ///groups of boxes sharing exactly the same position and size
+    Coincident,
+}
+
+///parameters shared by every scene
+#[derive(Debug, Clone)]
+pub struct SceneConfig {
+    pub count: usize,
+    pub range: Range<i32>, //world size on each axis
+    pub half_size: RangeInclusive<i32>, //usual half size of the boxes, some scenes go beyond it
+}
+
+impl Scene {
+    pub const ALL: [Scene; 6] = [Scene::Uniform, Scene::Clusters, Scene::Layers, Scene::Corridors, Scene::MixedSizes, Scene::Coincident];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scene::Uniform => "uniform",
+            Scene::Clusters => "clusters",
+            Scene::Layers => "layers",
+            Scene::Corridors => "corridors",
+            Scene::MixedSizes => "mixed-sizes",
+            Scene::Coincident => "coincident",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|scene| scene.name() == name)
+    }
+
+    pub fn generate(&self, rng: &mut impl Rng, config: &SceneConfig) -> Vec<AABB> {
+        match self {
+            Scene::Uniform => (0..config.count).map(|_| {
+                let center = new_fixed_vec(
+                    rng.gen_range(config.range.clone()),
+                    rng.gen_range(config.range.clone()),
+                    rng.gen_range(config.range.clone()),
+                );
+                AABB::from_center(center, random_half_size(rng, config))
+            }).collect(),
+            Scene::Clusters => {
+                let cluster_count = (config.count / 1000).max(1);
+                let centers: Vec<EntityPos> = (0..cluster_count).map(|_| random_pos(rng, &config.range)).collect();
+                let radius = I32F32::from_num(*config.half_size.end() * 4 + 1);
+                (0..config.count).map(|_| {
+                    if rng.gen_bool(0.1) { //a few isolated entities around the farms
+                        return AABB::from_center(random_pos(rng, &config.range), random_half_size(rng, config));
+                    }
+                    let cluster = centers[rng.gen_range(0..cluster_count)];
+                    let offset: EntityPos = new_fixed_vec(random_offset(rng, radius), random_offset(rng, radius), random_offset(rng, radius));
+                    AABB::from_center(cluster + offset, random_half_size(rng, config))
+                }).collect()
+            }
+            Scene::Layers => {
+                let layer_count = 4;
+                let layers: Vec<i32> = (0..layer_count).map(|_| rng.gen_range(config.range.clone())).collect();
+                (0..config.count).map(|_| {
+                    let half_size = random_half_size(rng, config);
+                    let mut center = random_pos(rng, &config.range);
+                    center.y = I32F32::from_num(layers[rng.gen_range(0..layer_count)]) + half_size.y; //standing on the layer
+                    AABB::from_center(center, half_size)
+                }).collect()
+            }
+            Scene::Corridors => {
+                let corridor_count = (config.count / 5000).max(1);
+                let width = I32F32::from_num(*config.half_size.end() * 2 + 1);
+                let corridors: Vec<(usize, EntityPos)> = (0..corridor_count).map(|_| (rng.gen_range(0..3), random_pos(rng, &config.range))).collect();
+                (0..config.count).map(|_| {
+                    let (axis, origin) = corridors[rng.gen_range(0..corridor_count)];
+                    let offset: EntityPos = new_fixed_vec(random_offset(rng, width), random_offset(rng, width), random_offset(rng, width));
+                    let mut center = origin + offset;
+                    center[axis] = random_coord(rng, &config.range);
+                    AABB::from_center(center, random_half_size(rng, config))
+                }).collect()
+            }
+            Scene::MixedSizes => (0..config.count).map(|_| {
+                let half_size = if rng.gen_bool(0.01) {
+                    let huge = *config.half_size.end() * 20 + 1;
+                    new_fixed_vec(rng.gen_range(1..=huge), rng.gen_range(1..=huge), rng.gen_range(1..=huge))
+                } else {
+                    let tiny = 1.0 / 16.0;
+                    new_fixed_vec(rng.gen_range(tiny..1.0), rng.gen_range(tiny..1.0), rng.gen_range(tiny..1.0))
+                };
+                AABB::from_center(random_pos(rng, &config.range), half_size)
+            }).collect(),
+            Scene::Coincident => {
+                let distinct = (config.count / 8).max(1);
+                let boxes: Vec<AABB> = (0..distinct).map(|_| AABB::from_center(random_pos(rng, &config.range), random_half_size(rng, config))).collect();
+                (0..config.count).map(|_| boxes[rng.gen_range(0..distinct)]).collect()
+            }
+        }
+    }
+}
+
+fn random_coord(rng: &mut impl Rng, range: &Range<i32>) -> I32F32 {
+    I32F32::from_num(rng.gen_range(range.start as f64..range.end as f64))
+}
+
+///fractional position, entities are rarely aligned on blocks
+fn random_pos(rng: &mut impl Rng, range: &Range<i32>) -> EntityPos {
+    new_fixed_vec(random_coord(rng, range), random_coord(rng, range), random_coord(rng, range))
+}
+
+fn random_offset(rng: &mut impl Rng, radius: I32F32) -> I32F32 {
+    I32F32::from_num(rng.gen_range(-radius.to_num::<f64>()..=radius.to_num::<f64>()))
+}
+
+fn random_half_size(rng: &mut impl Rng, config: &SceneConfig) -> EntityPos {
+    new_fixed_vec(
+        rng.gen_range(config.half_size.clone()),
+        rng.gen_range(config.half_size.clone()),
+        rng.gen_range(config.half_size.clone()),
+    )
+}
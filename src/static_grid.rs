@@ -1,48 +1,136 @@
 use std::collections::HashMap;
+use std::ops::ControlFlow;
 use nalgebra::Vector3;
-use crate::position::{AABB, EntityPosExt};
+use crate::broadphase::{collector, counter, Broadphase};
+use crate::position::{AABB, EntityPos, EntityPosExt};
 
 pub const CELL_SIZE: i32 = 128;
 
+#[derive(Default)]
 pub struct GridTracker {
-    grid: HashMap<Vector3<i32>, Vec<AABB>>,
+    grid: HashMap<Vector3<i32>, Vec<(usize, AABB)>>,
+    len: usize,
 }
 
 impl GridTracker {
     pub fn new() -> Self {
         Self {
             grid: HashMap::new(),
+            len: 0,
         }
     }
 
-    pub fn insert(&mut self, aabb: AABB) {
-        let min = aabb.min().block_pos();
-        let max = aabb.max().block_pos();
+    ///insert a new box and return its index, boxes are numbered in insertion order
+    pub fn insert(&mut self, aabb: AABB) -> usize {
+        let index = self.len;
+        self.len += 1;
 
-        let min = min.map(|x| x / CELL_SIZE);
-        let max = max.map(|x| x / CELL_SIZE);
+        let min = cell(aabb.min());
+        let max = cell(aabb.max());
 
         for x in min.x..=max.x {
             for y in min.y..=max.y {
                 for z in min.z..=max.z {
                     let key = Vector3::new(x, y, z);
-                    self.grid.entry(key).or_insert_with(Vec::new).push(aabb);
+                    self.grid.entry(key).or_default().push((index, aabb));
                 }
             }
         }
+        index
     }
 
     pub fn get_collisions(&self) -> usize {
         let mut collisions = 0;
-        for (_, aabbs) in self.grid.iter() {
-            for (i, aabb1) in aabbs.iter().enumerate() {
-                for aabb2 in aabbs[i + 1..].iter() {
-                    if aabb1.intersects(aabb2) {
-                        collisions += 1;
+        let _ = self.visit_collisions(counter(&mut collisions));
+        collisions
+    }
+
+    ///every intersecting pair, as indices given by `insert`
+    pub fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(collector(&mut pairs));
+        pairs
+    }
+
+    ///call `visitor` with the indices of every intersecting pair, until it breaks
+    ///two boxes can share several cells, the pair is only reported by the cell holding the max corner of their overlap
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(usize, usize) -> ControlFlow<B>) -> ControlFlow<B> {
+        for (key, aabbs) in self.grid.iter() {
+            for (i, (index1, aabb1)) in aabbs.iter().enumerate() {
+                for (index2, aabb2) in aabbs[i + 1..].iter() {
+                    if aabb1.intersects(aabb2) && cell(&aabb1.max().zip_map(aabb2.max(), |a, b| a.min(b))) == *key {
+                        visitor(*index1, *index2)?;
                     }
                 }
             }
         }
-        collisions
+        ControlFlow::Continue(())
+    }
+
+    ///every box overlapping `region`, each one is only reported by the cell holding the max corner of its overlap with the region
+    ///the cells of the region are looked up, or every cell when the region spans more cells than there are
+    pub fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        let (min, max) = (cell(region.min()), cell(region.max()));
+        let span = (max - min).map(|size| size as u64 + 1);
+        let mut found = Vec::new();
+        let mut visit = |key: &Vector3<i32>, aabbs: &Vec<(usize, AABB)>| {
+            for (index, aabb) in aabbs {
+                if aabb.intersects(region) && cell(&aabb.max().zip_map(region.max(), |a, b| a.min(b))) == *key {
+                    found.push(*index);
+                }
+            }
+        };
+        if span.x.saturating_mul(span.y).saturating_mul(span.z) > self.grid.len() as u64 {
+            for (key, aabbs) in self.grid.iter() {
+                visit(key, aabbs);
+            }
+        } else {
+            for x in min.x..=max.x {
+                for y in min.y..=max.y {
+                    for z in min.z..=max.z {
+                        let key = Vector3::new(x, y, z);
+                        if let Some(aabbs) = self.grid.get(&key) {
+                            visit(&key, aabbs);
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+///cell holding this position, rounded toward -inf so the cells don't straddle zero
+fn cell(pos: &EntityPos) -> Vector3<i32> {
+    pos.block_pos().map(|x| x.div_euclid(CELL_SIZE))
+}
+
+impl Broadphase for GridTracker {
+    fn name(&self) -> &'static str {
+        "grid"
+    }
+
+    fn build(&mut self, leaves: Vec<AABB>) {
+        self.grid.clear();
+        self.len = 0;
+        for aabb in leaves {
+            self.insert(aabb);
+        }
+    }
+
+    fn get_collision(&self) -> usize {
+        self.get_collisions()
+    }
+
+    fn get_collision_pairs(&self) -> Vec<(usize, usize)> {
+        GridTracker::get_collision_pairs(self)
+    }
+
+    fn visit_collisions(&self, visitor: &mut dyn FnMut(usize, usize) -> ControlFlow<()>) -> ControlFlow<()> {
+        GridTracker::visit_collisions(self, visitor)
+    }
+
+    fn query_aabb(&self, region: &AABB) -> Vec<usize> {
+        GridTracker::query_aabb(self, region)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,158 @@
+use fixed::types::I32F32;
+use crate::broadphase::Broadphase;
+use crate::hierarchy::Hierarchy;
+use crate::position::{EntityPos, AABB};
+
+///a box moving by `displacement` during one tick, times go from 0 at the start of the tick to 1 at its end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sweep {
+    aabb: AABB,
+    displacement: EntityPos,
+}
+
+impl Sweep {
+    pub fn new(aabb: AABB, displacement: EntityPos) -> Self {
+        Self {
+            aabb,
+            displacement,
+        }
+    }
+
+    ///box holding the whole movement, what the broadphase has to look at so fast movers can't tunnel
+    pub fn bounds(&self) -> AABB {
+        let end = AABB::new(
+            self.aabb.min().zip_map(&self.displacement, |a, b| a.saturating_add(b)),
+            self.aabb.max().zip_map(&self.displacement, |a, b| a.saturating_add(b)),
+        );
+        self.aabb.union(&end)
+    }
+
+    ///first time the moving box touches `other`, 0 if they already overlap, None if they never meet during the tick
+    ///slab test of the relative movement, like `Ray::intersect` against `other` grown by the size of the moving box
+    pub fn time_of_impact(&self, other: &AABB) -> Option<I32F32> {
+        let mut first = I32F32::ZERO;
+        let mut last = I32F32::ONE;
+        for axis in 0..3 {
+            let displacement = self.displacement[axis];
+            //the boxes overlap on this axis while min + t * d <= other.max and max + t * d >= other.min
+            let touching = other.min()[axis].saturating_sub(self.aabb.max()[axis]);
+            let leaving = other.max()[axis].saturating_sub(self.aabb.min()[axis]);
+            if displacement == I32F32::ZERO {
+                if touching > I32F32::ZERO || leaving < I32F32::ZERO {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = touching.saturating_div(displacement);
+            let t2 = leaving.saturating_div(displacement);
+            first = first.max(t1.min(t2));
+            last = last.min(t1.max(t2));
+            if first > last {
+                return None;
+            }
+        }
+        Some(first)
+    }
+
+    ///every entity hit during the tick with its time of impact, sorted by time then by entity index
+    pub fn query(&self, tree: &(impl Hierarchy + ?Sized)) -> Vec<(usize, I32F32)> {
+        let mut hits = Vec::new();
+        let mut stack: Vec<usize> = tree.root().into_iter().collect();
+        while let Some(node) = stack.pop() {
+            let Some(time) = self.time_of_impact(tree.node_aabb(node)) else { continue; }; //a child can't be hit if its parent isn't
+            match tree.children(node) {
+                None => hits.push((tree.entity(node), time)),
+                Some((left, right)) => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        hits.sort_unstable_by_key(|&(entity, time)| (time, entity));
+        hits
+    }
+}
+
+///every pair of moving entities meeting during the tick, with the time they first touch, sorted by time then by indices
+///`structure` is rebuilt from the swept bounds to find the candidates, then each pair is checked with the relative movement
+pub fn swept_pairs(structure: &mut dyn Broadphase, leaves: &[AABB], displacements: &[EntityPos]) -> Vec<(usize, usize, I32F32)> {
+    assert_eq!(leaves.len(), displacements.len());
+    let sweeps: Vec<Sweep> = leaves.iter().zip(displacements).map(|(aabb, displacement)| Sweep::new(*aabb, *displacement)).collect();
+    structure.build(sweeps.iter().map(Sweep::bounds).collect());
+
+    let mut pairs: Vec<(usize, usize, I32F32)> = structure.get_collision_pairs().into_iter().filter_map(|(a, b)| {
+        let (a, b) = (a.min(b), a.max(b));
+        let relative = displacements[a].zip_map(&displacements[b], |a, b| a.saturating_sub(b));
+        let time = Sweep::new(leaves[a], relative).time_of_impact(&leaves[b])?;
+        Some((a, b, time))
+    }).collect();
+    pairs.sort_unstable_by_key(|&(a, b, time)| (time, a, b));
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::brute_force::testing::{random_displacement, random_scene, LEAF_COUNTS, SEEDS};
+    use crate::position::new_fixed_vec;
+    use crate::{bvh4, bvh5};
+
+    fn unit_box(x: i32, y: i32, z: i32) -> AABB {
+        AABB::from_center(new_fixed_vec(x, y, z), new_fixed_vec(1, 1, 1))
+    }
+
+    #[test]
+    fn fast_mover_hits_a_thin_wall() {
+        let wall = AABB::from_center(new_fixed_vec(65, 0, 0), new_fixed_vec(0.5, 10, 10));
+        let projectile = Sweep::new(unit_box(0, 0, 0), new_fixed_vec(128, 0, 0));
+        assert!(!unit_box(0, 0, 0).intersects(&wall) && !unit_box(128, 0, 0).intersects(&wall), "both ends miss the wall");
+        assert_eq!(projectile.time_of_impact(&wall), Some(I32F32::from_num(63.5 / 128.0)));
+        assert!(projectile.bounds().intersects(&wall));
+    }
+
+    #[test]
+    fn times_of_impact() {
+        let sweep = Sweep::new(unit_box(0, 0, 0), new_fixed_vec(10, 0, 0));
+        assert_eq!(sweep.time_of_impact(&unit_box(0, 0, 0)), Some(I32F32::ZERO), "already overlapping");
+        assert_eq!(sweep.time_of_impact(&unit_box(12, 0, 0)), Some(I32F32::ONE), "touching at the end of the tick");
+        assert_eq!(sweep.time_of_impact(&unit_box(13, 0, 0)), None, "too far");
+        assert_eq!(sweep.time_of_impact(&unit_box(-3, 0, 0)), None, "behind");
+        assert_eq!(sweep.time_of_impact(&unit_box(6, 3, 0)), None, "beside");
+    }
+
+    #[test]
+    fn matches_oracle() {
+        let mut bvh4 = bvh4::BVH::new();
+        let mut bvh5 = bvh5::BVH::new();
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for count in LEAF_COUNTS {
+                let leaves = random_scene(&mut rng, count);
+                bvh4.build(leaves.clone());
+                bvh5.build(leaves.clone());
+                for _ in 0..8 {
+                    let sweep = Sweep::new(random_scene(&mut rng, 1)[0], random_displacement(&mut rng));
+                    let mut expected: Vec<(usize, I32F32)> = leaves.iter().enumerate().filter_map(|(i, aabb)| Some((i, sweep.time_of_impact(aabb)?))).collect();
+                    expected.sort_unstable_by_key(|&(entity, time)| (time, entity));
+                    assert_eq!(sweep.query(&bvh4), expected, "bvh4: wrong hits with seed {seed} and {count} leaves");
+                    assert_eq!(sweep.query(&bvh5), expected, "bvh5: wrong hits with seed {seed} and {count} leaves");
+                }
+
+                let displacements: Vec<EntityPos> = (0..count).map(|_| random_displacement(&mut rng)).collect();
+                let mut expected = Vec::new();
+                for a in 0..count {
+                    for b in a + 1..count {
+                        let relative = displacements[a] - displacements[b];
+                        if let Some(time) = Sweep::new(leaves[a], relative).time_of_impact(&leaves[b]) {
+                            expected.push((a, b, time));
+                        }
+                    }
+                }
+                expected.sort_unstable_by_key(|&(a, b, time)| (time, a, b));
+                assert_eq!(swept_pairs(&mut bvh5::BVH::new(), &leaves, &displacements), expected, "wrong swept pairs with seed {seed} and {count} leaves");
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::ops::ControlFlow;
+use fixed::types::{I32F32, I64F64};
+use crate::bipartite::{count_cross_collisions_par, get_cross_pairs_par, visit_cross_collisions};
+use crate::bvh5;
+use crate::culling::{cull, HalfSpace};
+use crate::hierarchy::query_aabb;
+use crate::nearest::{nearest, within_radius};
+use crate::position::{EntityPos, AABB};
+use crate::ray::Ray;
+use crate::sweep::Sweep;
+
+///an entity of a `World`, numbered in its own set, the dynamic ones sort first when two results are tied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Entity {
+    Dynamic(usize), //index in the vec given to the last `update`
+    Static(usize),
+}
+
+///entities which never move kept in their own tree, built again only when they change, next to a tree of the moving ones built every tick
+///static entities never collide with each other, so the pairs are dynamic against dynamic and dynamic against static
+#[derive(Default)]
+pub struct World {
+    statics: Vec<AABB>,
+    static_tree: bvh5::BVH,
+    static_changed: bool,
+    static_builds: usize,
+    dynamic_tree: bvh5::BVH,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn static_count(&self) -> usize {
+        self.statics.len()
+    }
+
+    ///how many times the static tree was built, for the benchmarks
+    pub fn static_builds(&self) -> usize {
+        self.static_builds
+    }
+
+    ///replace every static entity, they are numbered by their index in `leaves`
+    pub fn set_statics(&mut self, leaves: Vec<AABB>) {
+        self.statics = leaves;
+        self.static_changed = true;
+    }
+
+    pub fn add_static(&mut self, aabb: AABB) -> usize {
+        self.statics.push(aabb);
+        self.static_changed = true;
+        self.statics.len() - 1
+    }
+
+    pub fn set_static(&mut self, index: usize, aabb: AABB) {
+        self.statics[index] = aabb;
+        self.static_changed = true;
+    }
+
+    ///the last static entity takes the index of the removed one, its old index is returned if it wasn't the removed one
+    pub fn remove_static(&mut self, index: usize) -> Option<usize> {
+        self.statics.swap_remove(index);
+        self.static_changed = true;
+        (index < self.statics.len()).then_some(self.statics.len())
+    }
+
+    ///start a tick, with the boxes of every dynamic entity, the static tree is built too if the static entities changed
+    ///the dynamic tree is rebuilt from the previous tick's order, see `bvh5::BVH::rebuild`
+    pub fn update(&mut self, dynamic: Vec<AABB>) {
+        if self.static_changed {
+            self.static_tree.build(self.statics.clone());
+            self.static_changed = false;
+            self.static_builds += 1;
+        }
+        self.dynamic_tree.rebuild(dynamic);
+    }
+
+    pub fn get_collision(&self) -> usize {
+        let mut output = 0;
+        let _ = self.visit_collisions(|_, _| {
+            output += 1;
+            ControlFlow::<()>::Continue(())
+        });
+        output
+    }
+
+    pub fn get_collision_par(&self) -> usize {
+        self.dynamic_tree.get_collision_par() + count_cross_collisions_par(&self.dynamic_tree, &self.static_tree)
+    }
+
+    ///every intersecting pair as of the last update, the first entity is always dynamic
+    pub fn get_collision_pairs(&self) -> Vec<(usize, Entity)> {
+        let mut pairs = Vec::new();
+        let _ = self.visit_collisions(|a, b| {
+            pairs.push((a, b));
+            ControlFlow::<()>::Continue(())
+        });
+        pairs
+    }
+
+    pub fn get_collision_pairs_par(&self) -> Vec<(usize, Entity)> {
+        let mut pairs: Vec<(usize, Entity)> = self.dynamic_tree.get_collision_pairs_par().into_iter().map(|(a, b)| (a, Entity::Dynamic(b))).collect();
+        pairs.extend(get_cross_pairs_par(&self.dynamic_tree, &self.static_tree).into_iter().map(|(a, b)| (a, Entity::Static(b))));
+        pairs
+    }
+
+    ///call `visitor` with a dynamic entity and the one it intersects for every pair, until it breaks
+    pub fn visit_collisions<B>(&self, mut visitor: impl FnMut(usize, Entity) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.dynamic_tree.visit_collisions(|a, b| visitor(a, Entity::Dynamic(b)))?;
+        visit_cross_collisions(&self.dynamic_tree, &self.static_tree, |a, b| visitor(a, Entity::Static(b)))
+    }
+
+    ///every entity whose box overlaps `region`, dynamic ones first
+    pub fn query_aabb(&self, region: &AABB) -> Vec<Entity> {
+        let mut found: Vec<Entity> = query_aabb(&self.dynamic_tree, region).into_iter().map(Entity::Dynamic).collect();
+        found.extend(query_aabb(&self.static_tree, region).into_iter().map(Entity::Static));
+        found
+    }
+
+    ///closest entity hit in either tree, see `Ray::cast_closest`
+    pub fn cast_closest(&self, ray: &Ray) -> Option<(Entity, I32F32)> {
+        let dynamic = ray.cast_closest(&self.dynamic_tree).map(|(entity, distance)| (Entity::Dynamic(entity), distance));
+        let fixed = ray.cast_closest(&self.static_tree).map(|(entity, distance)| (Entity::Static(entity), distance));
+        dynamic.into_iter().chain(fixed).min_by_key(|&(entity, distance)| (distance, entity))
+    }
+
+    ///every entity hit in either tree, sorted by distance then by entity
+    pub fn cast_all(&self, ray: &Ray) -> Vec<(Entity, I32F32)> {
+        let mut hits: Vec<(Entity, I32F32)> = ray.cast_all(&self.dynamic_tree).into_iter().map(|(entity, distance)| (Entity::Dynamic(entity), distance)).collect();
+        hits.extend(ray.cast_all(&self.static_tree).into_iter().map(|(entity, distance)| (Entity::Static(entity), distance)));
+        hits.sort_unstable_by_key(|&(entity, distance)| (distance, entity));
+        hits
+    }
+
+    ///the `k` entities closest to `point` among both trees, see `nearest::nearest`
+    pub fn nearest(&self, point: &EntityPos, k: usize) -> Vec<(Entity, I64F64)> {
+        let mut found: Vec<(Entity, I64F64)> = nearest(&self.dynamic_tree, point, k).into_iter().map(|(entity, distance)| (Entity::Dynamic(entity), distance)).collect();
+        found.extend(nearest(&self.static_tree, point, k).into_iter().map(|(entity, distance)| (Entity::Static(entity), distance)));
+        found.sort_unstable_by_key(|&(entity, distance)| (distance, entity));
+        found.truncate(k);
+        found
+    }
+
+    ///an entity hit in either tree, the dynamic tree is searched first, see `Ray::cast_any`
+    pub fn cast_any(&self, ray: &Ray) -> Option<Entity> {
+        ray.cast_any(&self.dynamic_tree).map(Entity::Dynamic).or_else(|| ray.cast_any(&self.static_tree).map(Entity::Static))
+    }
+
+    ///every entity at most `radius` away from `point` in both trees, sorted by distance then by entity
+    pub fn within_radius(&self, point: &EntityPos, radius: I32F32) -> Vec<(Entity, I64F64)> {
+        let mut found: Vec<(Entity, I64F64)> = within_radius(&self.dynamic_tree, point, radius).into_iter().map(|(entity, distance)| (Entity::Dynamic(entity), distance)).collect();
+        found.extend(within_radius(&self.static_tree, point, radius).into_iter().map(|(entity, distance)| (Entity::Static(entity), distance)));
+        found.sort_unstable_by_key(|&(entity, distance)| (distance, entity));
+        found
+    }
+
+    ///every entity inside or straddling the volume, dynamic ones first, see `culling::cull`
+    pub fn cull(&self, half_spaces: &[HalfSpace]) -> Vec<Entity> {
+        let mut found: Vec<Entity> = cull(&self.dynamic_tree, half_spaces).into_iter().map(Entity::Dynamic).collect();
+        found.extend(cull(&self.static_tree, half_spaces).into_iter().map(Entity::Static));
+        found
+    }
+
+    ///every entity hit by the moving box during the tick in both trees, sorted by time of impact then by entity
+    pub fn sweep(&self, sweep: &Sweep) -> Vec<(Entity, I32F32)> {
+        let mut hits: Vec<(Entity, I32F32)> = sweep.query(&self.dynamic_tree).into_iter().map(|(entity, time)| (Entity::Dynamic(entity), time)).collect();
+        hits.extend(sweep.query(&self.static_tree).into_iter().map(|(entity, time)| (Entity::Static(entity), time)));
+        hits.sort_unstable_by_key(|&(entity, time)| (time, entity));
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::brute_force::testing::{random_coord, random_displacement, random_scene, LEAF_COUNTS, SEEDS};
+    use crate::culling::{classify, Containment};
+    use crate::nearest::distance_squared;
+    use crate::position::new_fixed_vec;
+
+    #[test]
+    fn matches_oracle() {
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut world = World::new();
+            let mut statics = random_scene(&mut rng, LEAF_COUNTS[seed as usize % LEAF_COUNTS.len()]);
+            world.set_statics(statics.clone());
+            let mut dynamics = random_scene(&mut rng, LEAF_COUNTS[(seed as usize + 3) % LEAF_COUNTS.len()]);
+            let mut expected_builds = 1;
+            for tick in 0..5 {
+                if tick == 3 {
+                    statics.push(random_scene(&mut rng, 1)[0]);
+                    assert_eq!(world.add_static(statics[statics.len() - 1]), statics.len() - 1);
+                    statics.swap_remove(0);
+                    assert_eq!(world.remove_static(0), (!statics.is_empty()).then_some(statics.len()));
+                    expected_builds += 1;
+                }
+                for aabb in dynamics.iter_mut() {
+                    let displacement = random_displacement(&mut rng);
+                    *aabb = AABB::new(aabb.min() + displacement, aabb.max() + displacement);
+                }
+                world.update(dynamics.clone());
+                assert_eq!(world.static_builds(), expected_builds, "the static tree is only built when it changes");
+
+                //every entity in one list, the dynamic ones first
+                let all: Vec<(Entity, AABB)> = dynamics.iter().enumerate().map(|(i, aabb)| (Entity::Dynamic(i), *aabb))
+                    .chain(statics.iter().enumerate().map(|(i, aabb)| (Entity::Static(i), *aabb)))
+                    .collect();
+                let context = format!("seed {seed}, tick {tick}");
+                let mut expected = Vec::new();
+                for (i, &(a, aabb_a)) in all.iter().enumerate() {
+                    for &(b, aabb_b) in &all[i + 1..] {
+                        if let (Entity::Dynamic(a), true) = (a, aabb_a.intersects(&aabb_b)) {
+                            expected.push((a, b));
+                        }
+                    }
+                }
+                expected.sort_unstable();
+                let normalize = |pairs: Vec<(usize, Entity)>| {
+                    let mut pairs: Vec<(usize, Entity)> = pairs.into_iter().map(|(a, b)| match b {
+                        Entity::Dynamic(b) => (a.min(b), Entity::Dynamic(a.max(b))),
+                        b => (a, b),
+                    }).collect();
+                    pairs.sort_unstable();
+                    pairs
+                };
+                assert_eq!(normalize(world.get_collision_pairs()), expected, "wrong pairs with {context}");
+                assert_eq!(normalize(world.get_collision_pairs_par()), expected, "wrong par pairs with {context}");
+                assert_eq!(world.get_collision(), expected.len(), "wrong count with {context}");
+                assert_eq!(world.get_collision_par(), expected.len(), "wrong par count with {context}");
+
+                let region = random_scene(&mut rng, 1)[0];
+                let mut found = world.query_aabb(&region);
+                found.sort_unstable();
+                assert_eq!(found, all.iter().filter(|(_, aabb)| aabb.intersects(&region)).map(|(entity, _)| *entity).collect::<Vec<_>>(), "wrong region query with {context}");
+
+                let ray = Ray::segment(
+                    new_fixed_vec(random_coord(&mut rng, 40, false), random_coord(&mut rng, 40, false), random_coord(&mut rng, 40, false)),
+                    new_fixed_vec(random_coord(&mut rng, 40, true), random_coord(&mut rng, 40, true), random_coord(&mut rng, 40, true)),
+                );
+                let mut hits: Vec<(Entity, I32F32)> = all.iter().filter_map(|(entity, aabb)| Some((*entity, ray.intersect(aabb)?))).collect();
+                hits.sort_unstable_by_key(|&(entity, distance)| (distance, entity));
+                assert_eq!(world.cast_all(&ray), hits, "wrong hits with {context}");
+                assert_eq!(world.cast_closest(&ray), hits.first().copied(), "wrong closest hit with {context}");
+                match world.cast_any(&ray) {
+                    Some(entity) => assert!(hits.iter().any(|&(hit, _)| hit == entity), "{entity:?} isn't on the ray with {context}"),
+                    None => assert!(hits.is_empty(), "missed hit with {context}"),
+                }
+
+                let point: EntityPos = new_fixed_vec(random_coord(&mut rng, 40, true), random_coord(&mut rng, 40, true), random_coord(&mut rng, 40, true));
+                let mut closest: Vec<(Entity, I64F64)> = all.iter().map(|(entity, aabb)| (*entity, distance_squared(aabb, &point))).collect();
+                closest.sort_unstable_by_key(|&(entity, distance)| (distance, entity));
+                for k in [0, 1, 5, all.len() + 1] {
+                    assert_eq!(world.nearest(&point, k), closest[..k.min(all.len())], "wrong {k} nearest with {context}");
+                }
+                let radius = random_coord(&mut rng, 20, false).abs();
+                let radius_squared = I64F64::from_num(radius) * I64F64::from_num(radius);
+                let within: Vec<(Entity, I64F64)> = closest.iter().copied().filter(|&(_, distance)| distance <= radius_squared).collect();
+                assert_eq!(world.within_radius(&point, radius), within, "wrong entities within {radius} with {context}");
+
+                let volume = HalfSpace::from_aabb(&random_scene(&mut rng, 1)[0]);
+                let mut culled = world.cull(&volume);
+                culled.sort_unstable();
+                assert_eq!(culled, all.iter().filter(|(_, aabb)| classify(&volume, aabb) != Containment::Outside).map(|(entity, _)| *entity).collect::<Vec<_>>(), "wrong culled entities with {context}");
+
+                let sweep = Sweep::new(random_scene(&mut rng, 1)[0], random_displacement(&mut rng));
+                let mut swept: Vec<(Entity, I32F32)> = all.iter().filter_map(|(entity, aabb)| Some((*entity, sweep.time_of_impact(aabb)?))).collect();
+                swept.sort_unstable_by_key(|&(entity, time)| (time, entity));
+                assert_eq!(world.sweep(&sweep), swept, "wrong swept entities with {context}");
+            }
+        }
+    }
+}